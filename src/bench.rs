@@ -0,0 +1,333 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{Barrier, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep_until, timeout, Duration, Instant, MissedTickBehavior};
+
+use crate::tools::{
+    decrypt_handshake, decrypt_message, encrypt_handshake, encrypt_message, format_address,
+    get_timestamp, read_frame, write_frame, Handshake, Message, PING_SIGNAL, PONG_SIGNAL,
+};
+
+// Marks the messages sent by the harness, followed by the send time in microseconds
+const BENCH_PREFIX: &str = "bench";
+// Time given to messages still in flight once the clients stop sending
+const DRAIN_TIME: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Most messages per second from each client, one every millisecond
+pub const MAX_RATE: f64 = 1000.0;
+
+// Settings of a load test, filled from `crypted-messages bench` arguments
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub address: String,
+    pub port: u16,
+    pub key: String,
+    pub clients: usize,
+    pub rate: f64, // Messages per second sent by each client
+    pub duration: Duration,
+    pub size: usize, // Bytes of text in each message
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            address: "127.0.0.1".to_string(),
+            port: 0,
+            key: String::new(),
+            clients: 10,
+            rate: 1.0,
+            duration: Duration::from_secs(10),
+            size: 64,
+        }
+    }
+}
+
+// What a simulated client saw, merged into a single report at the end
+#[derive(Debug, Default)]
+struct Report {
+    connected: usize,
+    connect_errors: usize,
+    handshake_errors: usize,
+    sent: usize,
+    send_errors: usize,
+    received: usize,
+    decrypt_errors: usize,
+    disconnects: usize,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.connected += other.connected;
+        self.connect_errors += other.connect_errors;
+        self.handshake_errors += other.handshake_errors;
+        self.sent += other.sent;
+        self.send_errors += other.send_errors;
+        self.received += other.received;
+        self.decrypt_errors += other.decrypt_errors;
+        self.disconnects += other.disconnects;
+        self.latencies.extend(other.latencies);
+    }
+}
+
+// Runs the load test against a running server and prints the results
+pub async fn run(config: BenchConfig) -> Result<()> {
+    // Written so a NaN rate is refused too
+    if config.clients == 0 || !(config.rate > 0.0 && config.rate <= MAX_RATE) {
+        return Err(anyhow!(
+            "The bench needs at least one client and a rate above 0 and up to {}",
+            MAX_RATE
+        ));
+    }
+    println!(
+        "Benchmarking {} with {} client(s) sending {} message(s)/s each for {:?}",
+        format_address(&config.address, config.port),
+        config.clients,
+        config.rate,
+        config.duration
+    );
+
+    let config = Arc::new(config);
+    // Every client waits here once connected, so they all start sending together
+    let ready = Arc::new(Barrier::new(config.clients));
+    let epoch = Instant::now();
+
+    let mut clients = JoinSet::new();
+    for n in 0..config.clients {
+        let config = config.clone();
+        let ready = ready.clone();
+        clients.spawn(async move { run_client(n, &config, &ready, epoch).await });
+    }
+
+    let mut report = Report::default();
+    while let Some(result) = clients.join_next().await {
+        report.merge(result?);
+    }
+    print_report(&config, report);
+    Ok(())
+}
+
+async fn run_client(n: usize, config: &BenchConfig, ready: &Barrier, epoch: Instant) -> Report {
+    let mut report = Report::default();
+    let session = connect(n, config).await;
+    // Clients that failed still have to reach the barrier, or the others would wait forever
+    ready.wait().await;
+    let (reader, writer) = match session {
+        Ok(session) => session,
+        Err(ConnectError::Connect) => {
+            report.connect_errors += 1;
+            return report;
+        }
+        Err(ConnectError::Handshake) => {
+            report.handshake_errors += 1;
+            return report;
+        }
+    };
+    report.connected += 1;
+
+    let writer = Mutex::new(writer);
+    let deadline = Instant::now() + config.duration;
+    let (sent, received) = tokio::join!(
+        send_messages(config, &writer, deadline, epoch),
+        receive_messages(config, reader, &writer, deadline + DRAIN_TIME, epoch)
+    );
+    report.merge(sent);
+    report.merge(received);
+    report
+}
+
+enum ConnectError {
+    Connect,
+    Handshake,
+}
+
+// Connects and goes through the same handshake as the real client
+async fn connect(
+    n: usize,
+    config: &BenchConfig,
+) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf), ConnectError> {
+    let address = (config.address.as_str(), config.port);
+    let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return Err(ConnectError::Connect),
+    };
+    let _ = stream.set_nodelay(true);
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let handshake = Handshake::new(format!("bench-{}", n), 1024, None);
+    let frame = encrypt_handshake(&config.key, &handshake).map_err(|_| ConnectError::Handshake)?;
+    write_frame(&mut writer, &frame)
+        .await
+        .map_err(|_| ConnectError::Handshake)?;
+
+    // A refused client gets a message instead of the handshake response
+    match timeout(CONNECT_TIMEOUT, read_frame(&mut reader)).await {
+        Ok(Ok(Some(frame))) if decrypt_handshake(&config.key, &frame).is_ok() => {
+            Ok((reader, writer))
+        }
+        _ => Err(ConnectError::Handshake),
+    }
+}
+
+async fn send_messages(
+    config: &BenchConfig,
+    writer: &Mutex<OwnedWriteHalf>,
+    deadline: Instant,
+    epoch: Instant,
+) -> Report {
+    let mut report = Report::default();
+    let mut ticker = interval(Duration::from_secs_f64(1.0 / config.rate));
+    // A server that can't keep up shows as latency, not as a burst of catch-up messages
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        let stamp = format!("{} {} ", BENCH_PREFIX, epoch.elapsed().as_micros());
+        let padding = "x".repeat(config.size.saturating_sub(stamp.len()));
+        let message = Message::new(None, Some(get_timestamp()), Some(stamp + &padding), None);
+        if send(config, writer, &message).await.is_err() {
+            report.send_errors += 1;
+            break;
+        }
+        report.sent += 1;
+    }
+    report
+}
+
+async fn receive_messages(
+    config: &BenchConfig,
+    mut reader: BufReader<OwnedReadHalf>,
+    writer: &Mutex<OwnedWriteHalf>,
+    until: Instant,
+    epoch: Instant,
+) -> Report {
+    let mut report = Report::default();
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut reader) => frame,
+            _ = sleep_until(until) => break,
+        };
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            _ => {
+                report.disconnects += 1;
+                break;
+            }
+        };
+        let Ok(message) = decrypt_message(&config.key, &frame) else {
+            report.decrypt_errors += 1;
+            continue;
+        };
+
+        let text = message.message.unwrap_or_default();
+        if text == PING_SIGNAL {
+            let pong = Message::new(None, Some(get_timestamp()), Some(PONG_SIGNAL.into()), None);
+            let _ = send(config, writer, &pong).await;
+            continue;
+        }
+        if let Some(sent_at) = parse_stamp(&text) {
+            report.received += 1;
+            report
+                .latencies
+                .push(epoch.elapsed().saturating_sub(sent_at));
+        }
+    }
+    report
+}
+
+async fn send(
+    config: &BenchConfig,
+    writer: &Mutex<OwnedWriteHalf>,
+    message: &Message,
+) -> Result<()> {
+    let frame = encrypt_message(&config.key, message)?;
+    write_frame(&mut *writer.lock().await, &frame).await?;
+    Ok(())
+}
+
+// Time since the start of the run at which a harness message was sent
+fn parse_stamp(text: &str) -> Option<Duration> {
+    let mut parts = text.split(' ');
+    if parts.next() != Some(BENCH_PREFIX) {
+        return None;
+    }
+    parts.next()?.parse().ok().map(Duration::from_micros)
+}
+
+// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn print_report(config: &BenchConfig, mut report: Report) {
+    let seconds = config.duration.as_secs_f64();
+    // Each message goes to every other connected client
+    let expected = report.sent * report.connected.saturating_sub(1);
+    report.latencies.sort();
+
+    println!(
+        "Clients:    {} connected, {} failed",
+        report.connected,
+        report.connect_errors + report.handshake_errors
+    );
+    println!(
+        "Sent:       {} messages ({:.1}/s of {:.1}/s targeted)",
+        report.sent,
+        report.sent as f64 / seconds,
+        config.rate * config.clients as f64
+    );
+    println!(
+        "Delivered:  {} of {} expected ({:.1}/s)",
+        report.received,
+        expected,
+        report.received as f64 / seconds
+    );
+    println!(
+        "Latency:    p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&report.latencies, 50.0),
+        percentile(&report.latencies, 90.0),
+        percentile(&report.latencies, 99.0),
+        report.latencies.last().copied().unwrap_or_default()
+    );
+    println!(
+        "Errors:     {} connect, {} handshake, {} send, {} decrypt, {} disconnected early",
+        report.connect_errors,
+        report.handshake_errors,
+        report.send_errors,
+        report.decrypt_errors,
+        report.disconnects
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+
+        assert_eq!(
+            parse_stamp("bench 1500 xxxx"),
+            Some(Duration::from_micros(1500))
+        );
+        assert_eq!(parse_stamp("hello 1500"), None);
+    }
+}
@@ -1,116 +1,343 @@
 use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
     execute,
-    style::{Color, Print, ResetColor, SetForegroundColor},
-    terminal::{Clear, ClearType},
+    style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor},
+    terminal::{self, Clear, ClearType},
     ExecutableCommand,
 };
+use std::fmt;
+use std::ops::Range;
 use std::sync::Arc;
-use std::{collections::VecDeque, str};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    str,
+};
 use std::{error::Error as StdError, time::Instant};
-use std::{io::Write, process};
+use std::{
+    io::{IsTerminal, Write},
+    process::{self, Stdio},
+};
 use tokio::net::TcpStream;
-use tokio::spawn;
 use tokio::sync::{mpsc, Mutex};
 use tokio::time::{self, timeout, Duration};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, BufReader},
     time::sleep,
 };
+use tokio::{spawn, task};
 
+use crate::discovery::{discover, DiscoveredServer, DISCOVERY_WAIT};
+use crate::profiles::{self, ClientSettings};
 use crate::tools::{
-    decrypt_handshake, decrypt_message, encrypt_handshake, encrypt_message, get_ip, get_port,
-    get_timestamp, AdressMode, ClientCommand, Handshake, Message, SerdeColor,
+    check_cooldown, decrypt_handshake, decrypt_message, encrypt_handshake, encrypt_message,
+    format_address, generate_key, get_ip, get_port, get_timestamp, key_fingerprint, mention_spans,
+    mentions, read_frame, write_frame, AdressMode, ClientCommand, Handshake, HeartbeatConfig,
+    Message, SerdeColor, DELETE_SIGNAL, DELIVERED_SIGNAL, NAME_CHANGE_SIGNAL, PING_SIGNAL,
+    PONG_SIGNAL, REACTION_SIGNAL, READ_SIGNAL, SENT_SIGNAL, TYPING_SIGNAL,
 };
 
 type Instance = Arc<Mutex<(String, SerdeColor)>>;
 type Key = Arc<String>;
 type ColorBool = Arc<Mutex<bool>>;
+type TypingBool = Arc<Mutex<bool>>; // Whether typing indicators are sent (opt-in)
+type ReceiptsBool = Arc<Mutex<bool>>; // Whether read receipts are sent (opt-in)
+type SeenCounts = Arc<Mutex<BTreeMap<u64, usize>>>; // Read counts of own messages not shown yet
+type LastSeen = Arc<Mutex<Option<u64>>>; // ID of the last message received from the server
+type LastHeard = Arc<Mutex<Instant>>; // When a frame was last received from the server
 const BUFFER_SIZE: usize = 1024;
 const CHUNK_SIZE: usize = 1024; // Define your chunk size
 const CHUNKED_SIGNAL: &str = "START_CHUNK";
 const FINAL_CHUNK_SIGNAL: &str = "END_CHUNK";
 const CLOSE_SIGNAL: &str = "CLOSE_CONNECTION";
 const COLOR_CHANGE_SIGNAL: &str = "COLOR_CHANGE";
-// const NAME_CHANGE_SIGNAL: &str = "NAME_CHANGE";
 const CONNECTION_TIMEOUT: u64 = 30;
-const RETRY_DELAY: u64 = 3; // Delay between connection attempts (in seconds)
+const RECONNECT_TIMEOUT: u64 = 300; // How long to keep trying after losing the connection
+const RETRY_DELAY: u64 = 1; // Initial delay between connection attempts (in seconds)
+const MAX_RETRY_DELAY: u64 = 30; // Upper bound for the exponential backoff (in seconds)
+const TYPING_INTERVAL: Duration = Duration::from_secs(3); // Time between typing notifications
+const SEEN_INTERVAL: Duration = Duration::from_secs(2); // Read counts are shown at most this often
 const HELP_MESSAGE: &str = "
 Commands:
 /toggle-color - Toggle color mode
 /change-color to change your color
 /view-messages to view your messages.
+/who - List connected users and how long they have been idle
+/away [reason] - Mark yourself as away
+/back - Mark yourself as back
+/status [text] - Set a custom status, or clear it when empty
+/nick <newname> - Change your name
+/edit <id> <text> - Change one of your messages, the #id is shown before each message
+/delete <id> - Delete one of your messages
+/reply <id> <text> - Answer a message, it is quoted above your reply
+/thread <id> - Show the conversation a message belongs to
+/react <id> <emoji> - React to a message, the same reaction again takes it back
+/msg <name> <text> - Send a direct message, only you and them see it
+/receipts - Toggle letting others know when you read their messages
+/mentions - List the latest messages that mention you as @name
+/search <words> [from:<name>] [since:<YYYY-MM-DD>] [page:<n>] - Search the stored messages
+/typing - Toggle letting others know while you type (interactive terminals only)
 /help - Show this help message
 /sudo (password) - Be granted admin privileges
 /close - Close gracefully the connection
 /quit - Forcefully quit the application
 ";
 
-pub async fn main_client() -> Result<(), Box<dyn StdError + Send + Sync>> {
-    let server_ip = get_ip(None, None, AdressMode::Client)?;
-    let server_port = get_port(None, None, AdressMode::Client)?;
+pub async fn main_client(settings: ClientSettings) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    // Anything the profile leaves out is asked for
+    let server_ip = get_ip(settings.address.as_deref(), None, AdressMode::Client)?;
+    let server_port = get_port(
+        settings.port.map(|port| port.to_string()),
+        None,
+        AdressMode::Client,
+    )?;
 
     // Keep trying to connect to the server with a 30-second timeout
-    let socket = wait_for_server(&server_ip, server_port).await?;
+    let mut socket = wait_for_server(&server_ip, server_port, CONNECTION_TIMEOUT).await?;
 
-    let (reader, mut writer) = socket.into_split();
-    let (tx, rx) = mpsc::unbounded_channel();
-    let mut reader = BufReader::new(reader);
+    let (tx, mut rx) = mpsc::unbounded_channel();
 
     // Set key and instance (name + color)
-    let key: Key = set_key().await?;
-    let instance: Instance = set_name().await?;
-    let color_bool = Arc::new(Mutex::new(true));
-    let color_bool_clone = color_bool.clone();
-
-    let mut buffer = [0u8; BUFFER_SIZE];
-
-    // Send initial handshake to server
-    send_initial_handshake(&key, &instance, &mut writer).await?;
+    let key: Key = set_key(settings.key).await?;
+    if let Some(expected) = &settings.fingerprint {
+        if key_fingerprint(&key) != *expected {
+            return Err(format!(
+                "This key doesn't match the server's fingerprint {}",
+                expected
+            )
+            .into());
+        }
+    }
+    let instance: Instance = set_name(settings.name).await?;
+    let color_bool = Arc::new(Mutex::new(settings.color));
+    let last_seen: LastSeen = Arc::new(Mutex::new(None));
+    let receipts_bool: ReceiptsBool = Arc::new(Mutex::new(false));
+    // Sent on every connection, so the server knows our messages and what it held for us
+    // Without a saved one we are someone new on every run
+    let identity = profiles::load_identity().unwrap_or_else(|e| {
+        eprintln!("Using a new identity for this session: {:#}", e);
+        generate_key(32)
+    });
 
     // Task to handle input from stdin and send to the server
     let tx_clone = tx.clone();
+    let typing_bool: TypingBool = Arc::new(Mutex::new(false));
     spawn(async move {
-        handle_stdin_input(tx_clone).await.unwrap_or_else(|e| {
-            eprintln!("Error handling stdin input: {:?}", e);
-        });
+        handle_stdin_input(tx_clone, typing_bool)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Error handling stdin input: {:?}", e);
+            });
     });
 
+    // Lines typed while reconnecting stay queued in the channel and are sent once resumed
+    // Only a session that got through the handshake is resumed, a failed first one is final
+    // unless the server asked us to try again later
+    let mut resuming = false;
+    let mut refused_delay = RETRY_DELAY;
+    // A single use invite is spent by the first session, reconnecting doesn't need it
+    let mut invite = settings.invite;
+    loop {
+        match run_session(
+            socket,
+            &key,
+            &instance,
+            &tx,
+            &mut rx,
+            color_bool.clone(),
+            &receipts_bool,
+            &last_seen,
+            settings.heartbeat,
+            &mut resuming,
+            invite.clone(),
+            &identity,
+            settings.notify.clone(),
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => match e.downcast_ref::<Refused>() {
+                // A full server may have room later, back off so it isn't hammered
+                Some(refused) if refused.retry => {
+                    let notice = format!(
+                        "[Refused] {}, trying again in {} second(s)",
+                        refused.reason, refused_delay
+                    );
+                    let _ = print_colored_text(&notice, Color::Red, color_bool.clone()).await;
+                    sleep(Duration::from_secs(refused_delay)).await;
+                    refused_delay = (refused_delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Some(_) => {
+                    let _ = terminal::disable_raw_mode();
+                    return Err(e);
+                }
+                None if !resuming => return Err(e),
+                None => {
+                    refused_delay = RETRY_DELAY;
+                    let _ = print_colored_text(
+                        &format!("[Connection lost] {}", e),
+                        Color::Red,
+                        color_bool.clone(),
+                    )
+                    .await;
+                }
+            },
+        }
+        if resuming {
+            invite = None;
+        }
+
+        println!("Reconnecting to the server...");
+        socket = wait_for_server(&server_ip, server_port, RECONNECT_TIMEOUT).await?;
+    }
+}
+
+// The server turned us away, only worth trying again when `retry` is set
+#[derive(Debug)]
+struct Refused {
+    reason: String,
+    retry: bool,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection refused by the server: {}", self.reason)
+    }
+}
+
+impl StdError for Refused {}
+
+// Run a single connection to the server until it drops, `resuming` is set once it is established
+// Returns Ok only when there is nothing left to send (stdin closed)
+#[allow(clippy::too_many_arguments)]
+async fn run_session(
+    socket: TcpStream,
+    key: &Key,
+    instance: &Instance,
+    tx: &mpsc::UnboundedSender<String>,
+    rx: &mut mpsc::UnboundedReceiver<String>,
+    color_bool: ColorBool,
+    receipts_bool: &ReceiptsBool,
+    last_seen: &LastSeen,
+    heartbeat: HeartbeatConfig,
+    resuming: &mut bool,
+    invite: Option<String>,
+    identity: &str,
+    notify: Option<String>,
+) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+
+    // Send initial handshake to server
+    send_initial_handshake(
+        key,
+        instance,
+        last_seen,
+        *resuming,
+        invite,
+        identity,
+        &mut writer,
+    )
+    .await?;
+
     // Read handshake response from the server with timeout
-    if let Err(_) = timeout(
+    timeout(
         Duration::from_secs(10),
-        handle_handshake_response(&key, &instance, &mut reader, &mut buffer),
+        handle_handshake_response(key, instance, last_seen, &mut reader),
     )
     .await
-    {
-        eprintln!("Server handshake timed out");
-        return Ok(());
-    }
+    .map_err(|_| "Server handshake timed out")??;
+    *resuming = true;
 
     let key_clone = key.clone();
-
-    let mut chunk_buffer = VecDeque::new();
-
+    let color_bool_clone = color_bool.clone();
     let instance_clone = instance.clone();
+    let last_seen_clone = last_seen.clone();
+    let tx_clone = tx.clone();
+    let last_heard: LastHeard = Arc::new(Mutex::new(Instant::now()));
+    let last_heard_clone = last_heard.clone();
+    let receipts_bool_clone = receipts_bool.clone();
+    let seen_counts: SeenCounts = Arc::new(Mutex::new(BTreeMap::new()));
+    let seen_counts_clone = seen_counts.clone();
 
     // Task to handle incoming server messages
-    spawn(async move {
-        if let Err(e) = handle_incoming_messages(
+    let mut incoming_task = spawn(async move {
+        let mut chunk_buffer = VecDeque::new();
+        handle_incoming_messages(
             key_clone,
             &mut reader,
             &mut chunk_buffer,
             color_bool_clone,
             &instance_clone,
+            &last_seen_clone,
+            &tx_clone,
+            &last_heard_clone,
+            &receipts_bool_clone,
+            &seen_counts_clone,
+            notify,
         )
         .await
-        {
-            eprintln!("Error handling incoming messages: {:?}", e);
-        }
     });
+    let seen_task = spawn(show_seen_counts(seen_counts, color_bool.clone()));
 
-    // Sending messages to the server
-    send_messages_to_server(rx, &key, &instance, &mut writer, color_bool).await?;
+    // Sending messages to the server until either side of the connection fails
+    let result = tokio::select! {
+        result = &mut incoming_task => {
+            seen_task.abort();
+            return result?;
+        }
+        result = send_messages_to_server(
+            rx,
+            key,
+            instance,
+            &mut writer,
+            color_bool,
+            receipts_bool,
+        ) => result,
+        result = run_heartbeat(tx, &last_heard, heartbeat) => result,
+    };
+    incoming_task.abort();
+    seen_task.abort();
+    result
+}
 
-    Ok(())
+// Shows how many have read each of the user's messages to the room
+// Counts are gathered for a while so a busy room doesn't print a line per reader
+async fn show_seen_counts(seen_counts: SeenCounts, color_bool: ColorBool) {
+    let mut ticker = time::interval(SEEN_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let counts = std::mem::take(&mut *seen_counts.lock().await);
+        for (id, count) in counts {
+            let line = format!("#{} seen by {}", id, count);
+            let _ = print_colored_text(&line, Color::DarkGrey, color_bool.clone()).await;
+        }
+    }
+}
+
+// Ping the server every interval, fails once the server has been silent for too long
+async fn run_heartbeat(
+    tx: &mpsc::UnboundedSender<String>,
+    last_heard: &LastHeard,
+    heartbeat: HeartbeatConfig,
+) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let mut ticker = time::interval(heartbeat.interval);
+
+    loop {
+        ticker.tick().await;
+
+        if last_heard.lock().await.elapsed() > heartbeat.timeout() {
+            return Err(format!(
+                "No answer from the server in {} seconds",
+                heartbeat.timeout().as_secs()
+            )
+            .into());
+        }
+
+        // Pings go through the same queue as typed lines
+        if tx.send(PING_SIGNAL.to_string()).is_err() {
+            return Err("Failed to queue heartbeat".into());
+        }
+    }
 }
 
 async fn toogle_color(color_bool: ColorBool) {
@@ -120,16 +347,27 @@ async fn toogle_color(color_bool: ColorBool) {
 }
 
 // Function to send the initial handshake to the server
+// When resuming, the same name and color are requested along with the last message seen
 async fn send_initial_handshake(
     key: &Key,
     instance: &Instance,
+    last_seen: &LastSeen,
+    resuming: bool,
+    invite: Option<String>,
+    identity: &str,
     writer: &mut tokio::net::tcp::OwnedWriteHalf,
 ) -> Result<(), Box<dyn StdError + Send + Sync>> {
-    let handshake = Handshake::new(instance.lock().await.0.clone(), BUFFER_SIZE, None);
-    let encrypted_handshake = encrypt_handshake(&key, &handshake)?;
+    let (name, color) = instance.lock().await.clone();
+    let mut handshake = Handshake::new(name, BUFFER_SIZE, None);
+    if resuming {
+        handshake.color = Some(color);
+        handshake.last_seen = *last_seen.lock().await;
+    }
+    handshake.invite = invite;
+    handshake.identity = Some(identity.to_string());
+    let encrypted_handshake = encrypt_handshake(key, &handshake)?;
 
-    writer.write_all(&encrypted_handshake).await?;
-    writer.flush().await?;
+    write_frame(writer, &encrypted_handshake).await?;
 
     Ok(())
 }
@@ -138,16 +376,31 @@ async fn send_initial_handshake(
 async fn handle_handshake_response(
     key: &Key,
     instance: &Instance,
+    last_seen: &LastSeen,
     reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
-    buffer: &mut [u8],
 ) -> Result<(), Box<dyn StdError + Send + Sync>> {
-    match reader.read(buffer).await {
-        Ok(0) => {
+    match read_frame(reader).await {
+        Ok(None) => {
             eprintln!("Server disconnected during handshake");
-            return Ok(());
+            return Err("Server disconnected during handshake".into());
         }
-        Ok(n) => {
-            let handshake = decrypt_handshake(&key, &buffer[..n])?;
+        Ok(Some(frame)) => {
+            // A server that turns us away answers with a message explaining why,
+            // retrying right away would only be refused again
+            let handshake = match decrypt_handshake(key, &frame) {
+                Ok(handshake) => handshake,
+                Err(e) => match decrypt_message(key, &frame) {
+                    Ok(Message {
+                        message: Some(reason),
+                        retry,
+                        ..
+                    }) => {
+                        let retry = retry == Some(true);
+                        return Err(Box::new(Refused { reason, retry }));
+                    }
+                    _ => return Err(e.into()),
+                },
+            };
             if handshake.name == instance.lock().await.0 {
                 println!("Handshake successful");
                 instance.lock().await.1 = handshake.color.unwrap_or(SerdeColor::Red);
@@ -156,12 +409,17 @@ async fn handle_handshake_response(
                 println!("Updating name: {}", handshake.name);
                 instance.lock().await.0 = handshake.name;
                 instance.lock().await.1 = handshake.color.unwrap_or(SerdeColor::Red);
-                return Ok(());
+            }
+
+            // Start counting from the server's latest message, or restart if the server did
+            let mut last_seen = last_seen.lock().await;
+            if last_seen.is_none() || *last_seen > handshake.last_seen {
+                *last_seen = handshake.last_seen;
             }
         }
         Err(e) => {
             eprintln!("Failed to read handshake response from server: {:?}", e);
-            return Ok(());
+            return Err(e.into());
         }
     }
     Ok(())
@@ -170,19 +428,50 @@ async fn handle_handshake_response(
 // Task to handle reading from stdin and sending chunked messages to a channel
 async fn handle_stdin_input(
     tx: mpsc::UnboundedSender<String>,
+    typing_bool: TypingBool,
 ) -> Result<(), Box<dyn StdError + Send + Sync>> {
     let mut stdin = BufReader::new(tokio::io::stdin()).take(usize::MAX as u64);
     let mut buffer = Vec::new();
+    // Typing indicators need every keystroke, which only a terminal in raw mode provides
+    let is_terminal = std::io::stdin().is_terminal();
 
     loop {
         buffer.clear();
-        let bytes_read = stdin.read_buf(&mut buffer).await?;
+        let bytes_read = if is_terminal && *typing_bool.lock().await {
+            let tx_clone = tx.clone();
+            match task::spawn_blocking(move || read_line_raw(&tx_clone)).await?? {
+                Some(line) => {
+                    buffer.extend_from_slice(line.as_bytes());
+                    buffer.push(b'\n');
+                    buffer.len()
+                }
+                None => 0,
+            }
+        } else {
+            stdin.read_buf(&mut buffer).await?
+        };
 
         if bytes_read == 0 {
             // End of input
             break;
         }
 
+        // Toggled here rather than in the send loop so the next line is read in the right mode
+        let line = String::from_utf8_lossy(&buffer);
+        if let ClientCommand::ToggleTyping = ClientCommand::from_str(line.trim()) {
+            let mut typing_bool = typing_bool.lock().await;
+            if !is_terminal {
+                println!("Typing indicators need an interactive terminal");
+            } else if *typing_bool {
+                *typing_bool = false;
+                println!("Typing indicator disabled");
+            } else {
+                *typing_bool = true;
+                println!("Typing indicator enabled, others will see when you are typing");
+            }
+            continue;
+        }
+
         if buffer.len() > CHUNK_SIZE {
             println!("Sending chunked message to server");
 
@@ -240,32 +529,117 @@ async fn handle_stdin_input(
     Ok(())
 }
 
+// Read a line key by key in raw mode, queueing a typing notification while it is not empty
+// Returns None at the end of input (Ctrl+D on an empty line)
+fn read_line_raw(tx: &mpsc::UnboundedSender<String>) -> std::io::Result<Option<String>> {
+    terminal::enable_raw_mode()?;
+    let result = read_keys(tx);
+    terminal::disable_raw_mode()?;
+    execute!(std::io::stdout(), Print("\r\n"))?;
+    result
+}
+
+fn read_keys(tx: &mpsc::UnboundedSender<String>) -> std::io::Result<Option<String>> {
+    let mut stdout = std::io::stdout();
+    let mut line = String::new();
+    let mut last_typing = None;
+
+    loop {
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => return Ok(Some(line)),
+            KeyCode::Char('c') if ctrl => {
+                // Raw mode swallows SIGINT, so quit the same way /quit does
+                terminal::disable_raw_mode()?;
+                process::exit(0);
+            }
+            KeyCode::Char('d') if ctrl && line.is_empty() => return Ok(None),
+            KeyCode::Backspace => {
+                if line.pop().is_some() {
+                    execute!(stdout, Print("\u{8} \u{8}"))?;
+                }
+                continue;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                line.push(c);
+                execute!(stdout, Print(c))?;
+            }
+            _ => continue,
+        }
+
+        if check_cooldown(&mut last_typing, TYPING_INTERVAL).is_ok() {
+            let _ = tx.send(TYPING_SIGNAL.to_string());
+        }
+    }
+}
+
 // Task to handle incoming messages from the server
+#[allow(clippy::too_many_arguments)]
 async fn handle_incoming_messages(
     key: Key,
     reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
     chunk_buffer: &mut VecDeque<String>,
     color_bool: ColorBool,
     instance: &Instance,
+    last_seen: &LastSeen,
+    tx: &mpsc::UnboundedSender<String>,
+    last_heard: &LastHeard,
+    receipts_bool: &ReceiptsBool,
+    seen_counts: &SeenCounts,
+    notify: Option<String>,
 ) -> Result<(), Box<dyn StdError + Send + Sync>> {
-    let mut buffer = [0u8; BUFFER_SIZE];
-    // let mut chunk_buffer = VecDeque::new();
     let mut is_chunked_message = false;
 
     loop {
         let color_bool = color_bool.clone();
-        match reader.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
-                // When n is 0, the server has closed the connection gracefully
+        match read_frame(reader).await {
+            Ok(None) => {
+                // The server has closed the connection gracefully
                 eprintln!("Server disconnected gracefully");
                 return Err("Server disconnected".into());
             }
-            Ok(n) => {
+            Ok(Some(frame)) => {
+                // Any frame proves the server is still alive
+                *last_heard.lock().await = Instant::now();
+
                 // Handle the message if the server is still sending data
-                let decrypted_msg = decrypt_message(&key, &buffer[..n])?;
+                let mut decrypted_msg = decrypt_message(&key, &frame)?;
+
+                // Remember the newest stored message, skipping any already displayed
+                // Messages held while we were offline are older, but were never shown
+                if let (Some(id), None) = (decrypted_msg.id, decrypted_msg.queued) {
+                    let mut last_seen = last_seen.lock().await;
+                    if last_seen.is_some_and(|last_id| id <= last_id) {
+                        continue;
+                    }
+                    *last_seen = Some(id);
+                }
 
-                if let Some(message) = decrypted_msg.message {
+                if let Some(message) = decrypted_msg.message.take() {
                     match message.as_str() {
+                        PING_SIGNAL => {
+                            // Answer through the outgoing queue
+                            let _ = tx.send(PONG_SIGNAL.to_string());
+                        }
+                        PONG_SIGNAL => (),
+                        TYPING_SIGNAL => {
+                            let _ = print_colored_text(
+                                &format!(
+                                    "{} is typing...",
+                                    decrypted_msg.name.unwrap_or_else(|| "Someone".to_string())
+                                ),
+                                Color::DarkGrey,
+                                color_bool,
+                            )
+                            .await;
+                        }
                         CHUNKED_SIGNAL => {
                             // Start of a chunked message
                             println!("Starting to accumulate chunked messages");
@@ -303,7 +677,7 @@ async fn handle_incoming_messages(
                             std::io::stdout().flush().unwrap();
                             print!("\rClosed...         ");
                             // time::sleep(Duration::from_secs(1)).await;
-                            // FEATURE: Add restart option
+                            let _ = terminal::disable_raw_mode();
                             process::exit(0);
                         }
                         COLOR_CHANGE_SIGNAL => {
@@ -314,38 +688,156 @@ async fn handle_incoming_messages(
                                 &format!("Color changed to {:?}", new_color),
                                 Color::from(new_color),
                                 color_bool,
-                            );
+                            )
+                            .await;
+                        }
+                        NAME_CHANGE_SIGNAL => {
+                            // Change the name of the client
+                            let new_name = decrypted_msg
+                                .name
+                                .unwrap_or_else(|| "Unknown sender".to_string());
+                            instance.lock().await.0 = new_name.clone();
+                            let _ = print_colored_text(
+                                &format!("Name changed to {}", new_name),
+                                Color::from(decrypted_msg.color.unwrap_or(SerdeColor::Red)),
+                                color_bool,
+                            )
+                            .await;
+                        }
+                        REACTION_SIGNAL if decrypted_msg.target.is_some() => {
+                            let counts = match decrypted_msg.reaction_counts() {
+                                counts if counts.is_empty() => " [no reactions]".to_string(),
+                                counts => counts,
+                            };
+                            let _ = print_colored_text(
+                                &format!(
+                                    "#{} {}{}",
+                                    decrypted_msg.target.unwrap_or_default(),
+                                    decrypted_msg.quote.as_deref().unwrap_or_default(),
+                                    counts
+                                ),
+                                Color::DarkGrey,
+                                color_bool,
+                            )
+                            .await;
+                        }
+                        SENT_SIGNAL | DELIVERED_SIGNAL if decrypted_msg.target.is_some() => {
+                            let target = decrypted_msg.target.unwrap_or_default();
+                            let line =
+                                match (message.as_str(), decrypted_msg.to, decrypted_msg.name) {
+                                    (SENT_SIGNAL, Some(to), _) => {
+                                        format!("#{} sent to {}", target, to)
+                                    }
+                                    (SENT_SIGNAL, None, _) => format!("#{} sent", target),
+                                    (_, _, name) => format!(
+                                        "#{} delivered to {}",
+                                        target,
+                                        name.unwrap_or_else(|| "Unknown".to_string())
+                                    ),
+                                };
+                            let _ = print_colored_text(&line, Color::DarkGrey, color_bool).await;
+                        }
+                        READ_SIGNAL if decrypted_msg.target.is_some() => {
+                            let target = decrypted_msg.target.unwrap_or_default();
+                            match decrypted_msg.seen_by {
+                                // Messages to the room, shown a while later with the latest count
+                                Some(count) => {
+                                    seen_counts.lock().await.insert(target, count);
+                                }
+                                None => {
+                                    let line = format!(
+                                        "#{} read by {}",
+                                        target,
+                                        decrypted_msg.name.unwrap_or_else(|| "Unknown".to_string())
+                                    );
+                                    let _ = print_colored_text(&line, Color::DarkGrey, color_bool)
+                                        .await;
+                                }
+                            }
+                        }
+                        DELETE_SIGNAL if decrypted_msg.target.is_some() => {
+                            let _ = print_colored_text(
+                                &format!(
+                                    "#{} from {} was deleted",
+                                    decrypted_msg.target.unwrap_or_default(),
+                                    decrypted_msg
+                                        .name
+                                        .unwrap_or_else(|| "Unknown sender".to_string())
+                                ),
+                                Color::DarkGrey,
+                                color_bool,
+                            )
+                            .await;
                         }
-                        // NAME_CHANGE_SIGNAL => {
-                        //     // Change the name of the client
-                        //     let new_name = decrypted_msg
-                        //         .name
-                        //         .unwrap_or_else(|| "Unknown sender".to_string());
-                        //     instance.lock().await.0 = new_name.clone();
-                        //     let _ = print_colored_text(
-                        //         &format!("Name changed to {:?}", new_name),
-                        //         Color::from(decrypted_msg.color.unwrap_or(SerdeColor::Red)),
-                        //         color_bool,
-                        //     );
-                        // }
                         _ => {
                             if is_chunked_message {
                                 // Accumulate message as part of a chunked message
                                 chunk_buffer.push_back(message);
                             } else {
-                                // Display regular individual message
-                                let _ = print_colored_text(
-                                    &format!(
-                                        "{}: {}",
-                                        decrypted_msg
-                                            .name
-                                            .unwrap_or_else(|| "Unknown sender".to_string()),
-                                        message
-                                    ),
-                                    Color::from(decrypted_msg.color.unwrap_or(SerdeColor::Red)),
-                                    color_bool,
-                                )
-                                .await;
+                                // Replies show the start of the message they answer first
+                                if let Some(quote) = &decrypted_msg.quote {
+                                    let _ = print_colored_text(
+                                        &format!(
+                                            "  > #{} {}",
+                                            decrypted_msg.reply_to.unwrap_or_default(),
+                                            quote
+                                        ),
+                                        Color::DarkGrey,
+                                        color_bool.clone(),
+                                    )
+                                    .await;
+                                }
+                                // Display regular individual message, stored ones with their ID
+                                let counts = decrypted_msg.reaction_counts();
+                                let sender = decrypted_msg
+                                    .name
+                                    .unwrap_or_else(|| "Unknown sender".to_string());
+                                let direct = if decrypted_msg.to.is_some() {
+                                    " (direct)"
+                                } else {
+                                    ""
+                                };
+                                let (prefix, suffix) =
+                                    match (decrypted_msg.target, decrypted_msg.id) {
+                                        (Some(target), _) => {
+                                            (format!("#{} {} (edited): ", target, sender), "")
+                                        }
+                                        (None, Some(id)) => (
+                                            format!("#{} {}{}: ", id, sender, direct),
+                                            counts.as_str(),
+                                        ),
+                                        (None, None) => (format!("{}: ", sender), ""),
+                                    };
+                                let line = format!("{}{}{}", prefix, message, suffix);
+                                // New stored messages from others can mention us, and opted in
+                                // users tell their author they saw them
+                                let own_name = instance.lock().await.0.clone();
+                                let new_id = decrypted_msg.id.filter(|_| {
+                                    decrypted_msg.target.is_none() && sender != own_name
+                                });
+                                if let Some(id) = new_id {
+                                    if *receipts_bool.lock().await {
+                                        let _ = tx.send(format!("{} {}", READ_SIGNAL, id));
+                                    }
+                                }
+                                let color =
+                                    Color::from(decrypted_msg.color.unwrap_or(SerdeColor::Red));
+                                if new_id.is_some()
+                                    && mentions(&message).contains(&own_name.as_str())
+                                {
+                                    let spans: Vec<_> = mention_spans(&message, &own_name)
+                                        .into_iter()
+                                        .map(|span| {
+                                            span.start + prefix.len()..span.end + prefix.len()
+                                        })
+                                        .collect();
+                                    let _ =
+                                        print_highlighted_text(&line, &spans, color, color_bool)
+                                            .await;
+                                    notify_mention(notify.as_deref(), &sender, &message);
+                                } else {
+                                    let _ = print_colored_text(&line, color, color_bool).await;
+                                }
                             }
                         }
                     }
@@ -371,12 +863,14 @@ async fn handle_incoming_messages(
 }
 
 // Task to send messages to the server
+// Returns Ok when the input channel closes and Err when the server can't be written to
 async fn send_messages_to_server(
-    mut rx: mpsc::UnboundedReceiver<String>,
+    rx: &mut mpsc::UnboundedReceiver<String>,
     key: &Key,
     instance: &Instance,
     writer: &mut tokio::net::tcp::OwnedWriteHalf,
     color_bool: ColorBool,
+    receipts_bool: &ReceiptsBool,
 ) -> Result<(), Box<dyn StdError + Send + Sync>> {
     while let Some(line) = rx.recv().await {
         let instance_lock = instance.lock().await;
@@ -392,9 +886,19 @@ async fn send_messages_to_server(
             ClientCommand::Help => {
                 let _ = print_colored_text(HELP_MESSAGE, color, color_bool).await;
             }
+            ClientCommand::ToggleReceipts => {
+                let mut receipts_bool = receipts_bool.lock().await;
+                *receipts_bool = !*receipts_bool;
+                let notice = match *receipts_bool {
+                    true => "Read receipts enabled, others will see when you read their messages",
+                    false => "Read receipts disabled",
+                };
+                let _ = print_colored_text(notice, color, color_bool).await;
+            }
             ClientCommand::Quit => {
                 let _ = print_colored_text("Forcefully quitting...", color, color_bool).await;
                 sleep(Duration::from_secs(1)).await;
+                let _ = terminal::disable_raw_mode();
                 process::exit(0);
             }
             _ => {
@@ -407,11 +911,11 @@ async fn send_messages_to_server(
                             Some(instance_lock.1),
                         );
 
-                        let encrypted_chunk_message = encrypt_message(&key, &chunk_message)?;
+                        let encrypted_chunk_message = encrypt_message(key, &chunk_message)?;
 
-                        if writer.write_all(&encrypted_chunk_message).await.is_err() {
+                        if write_frame(writer, &encrypted_chunk_message).await.is_err() {
                             eprintln!("Failed to send chunked message to server");
-                            break;
+                            return Err("Failed to send chunked message to server".into());
                         }
                     }
                     FINAL_CHUNK_SIGNAL => {
@@ -422,32 +926,37 @@ async fn send_messages_to_server(
                             Some(instance_lock.1),
                         );
 
-                        let encrypted_final_chunk = encrypt_message(&key, &final_chunk_signal)?;
+                        let encrypted_final_chunk = encrypt_message(key, &final_chunk_signal)?;
 
-                        if writer.write_all(&encrypted_final_chunk).await.is_err() {
+                        if write_frame(writer, &encrypted_final_chunk).await.is_err() {
                             eprintln!("Failed to send final chunk signal to server");
-                            break;
+                            return Err("Failed to send final chunk signal to server".into());
                         }
 
                         println!("[Debug] Received chunked signal from client");
                         continue; // Optionally skip sending this message or handle it differently
                     }
                     _ => {
-                        // Handle regular messages
-                        let message = Message::new(
+                        // Handle regular messages, read receipts are queued as the signal and an ID
+                        let mut message = Message::new(
                             Some(instance_lock.0.clone()),
                             Some(get_timestamp()),
                             Some(line.clone()),
                             Some(instance_lock.1),
                         );
+                        let read = line.strip_prefix(READ_SIGNAL).map(str::trim);
+                        if let Some(Ok(target)) = read.map(str::parse) {
+                            message.message = Some(READ_SIGNAL.to_string());
+                            message.target = Some(target);
+                        }
 
                         // Encrypt the message
-                        let encrypted_message = encrypt_message(&key, &message)?;
+                        let encrypted_message = encrypt_message(key, &message)?;
 
                         // Send the encrypted message
-                        if writer.write_all(&encrypted_message).await.is_err() {
+                        if write_frame(writer, &encrypted_message).await.is_err() {
                             eprintln!("Failed to send message to server");
-                            break;
+                            return Err("Failed to send message to server".into());
                         }
                     }
                 }
@@ -457,19 +966,80 @@ async fn send_messages_to_server(
     Ok(())
 }
 
+// Lets the user know someone mentioned them: the bell, or their own notification command
+// The command gets who and what as environment variables, never spliced into the command line
+fn notify_mention(notify: Option<&str>, from: &str, text: &str) {
+    let Some(command) = notify else {
+        print!("\x07");
+        let _ = std::io::stdout().flush();
+        return;
+    };
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    // The child is not waited for, tokio reaps it once it exits
+    let spawned = tokio::process::Command::new(shell)
+        .args([flag, command])
+        .env("CM_FROM", from)
+        .env("CM_MESSAGE", text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(e) = spawned {
+        eprintln!("Failed to run the notification command: {}", e);
+    }
+}
+
 // Set the client's name
-async fn set_name() -> Result<Instance, Box<dyn StdError + Send + Sync>> {
-    let name = get_user_input(Some("Enter your name: "))?;
+async fn set_name(name: Option<String>) -> Result<Instance, Box<dyn StdError + Send + Sync>> {
+    let name = match name {
+        Some(name) => name,
+        None => get_user_input(Some("Enter your name: "))?,
+    };
     println!("Name set as: {}", name);
     Ok(Arc::new(Mutex::new((name, SerdeColor::Yellow))))
 }
 
 // Set the encryption key
-async fn set_key() -> Result<Key, Box<dyn StdError + Send + Sync>> {
-    let key = get_user_input(Some("Enter the key to connect to the server: "))?;
+async fn set_key(key: Option<String>) -> Result<Key, Box<dyn StdError + Send + Sync>> {
+    let key = match key {
+        Some(key) => key,
+        None => get_user_input(Some("Enter the key to connect to the server: "))?,
+    };
     Ok(Arc::new(key))
 }
 
+// Lists the servers announcing themselves on the LAN and asks which one to connect to
+pub async fn choose_server(port: u16) -> Result<DiscoveredServer, Box<dyn StdError + Send + Sync>> {
+    println!(
+        "Looking for servers on the local network for {}s...",
+        DISCOVERY_WAIT.as_secs()
+    );
+    let mut servers = discover(port, DISCOVERY_WAIT).await?;
+    if servers.is_empty() {
+        return Err("No servers found on the local network".into());
+    }
+    for (n, server) in servers.iter().enumerate() {
+        println!(
+            "{}. {} at {} (key fingerprint {})",
+            n + 1,
+            server.name,
+            format_address(&server.address.to_string(), server.port),
+            server.fingerprint
+        );
+    }
+    loop {
+        let choice = get_user_input(Some("Pick a server: "))?;
+        match choice.parse::<usize>() {
+            Ok(n) if (1..=servers.len()).contains(&n) => return Ok(servers.swap_remove(n - 1)),
+            _ => println!("Enter a number between 1 and {}.", servers.len()),
+        }
+    }
+}
+
 // Helper function to get user input from stdin
 fn get_user_input(prompt: Option<&str>) -> Result<String, std::io::Error> {
     if let Some(prompt) = prompt {
@@ -482,6 +1052,43 @@ fn get_user_input(prompt: Option<&str>) -> Result<String, std::io::Error> {
     Ok(input.trim().to_string())
 }
 
+// Prints a line like print_colored_text, with the byte ranges in `highlights` standing out
+// Reverse video in color mode, surrounded by asterisks otherwise
+async fn print_highlighted_text(
+    text: &str,
+    highlights: &[Range<usize>],
+    color: Color,
+    color_bool: ColorBool,
+) -> std::io::Result<()> {
+    if !*color_bool.lock().await {
+        let mut marked = text.to_string();
+        for span in highlights.iter().rev() {
+            marked.insert(span.end, '*');
+            marked.insert(span.start, '*');
+        }
+        return print_colored_text(&marked, color, color_bool).await;
+    }
+
+    let mut stdout = std::io::stdout();
+    stdout.execute(SetForegroundColor(color))?;
+    let mut printed = 0;
+    for span in highlights {
+        execute!(
+            stdout,
+            Print(text[printed..span.start].replace('\n', "\r\n")),
+            SetAttribute(Attribute::Reverse),
+            Print(&text[span.clone()]),
+            SetAttribute(Attribute::NoReverse)
+        )?;
+        printed = span.end;
+    }
+    execute!(stdout, Print(text[printed..].replace('\n', "\r\n")))?;
+    execute!(stdout, Print("\r\n"))?;
+    stdout.execute(ResetColor)?;
+    execute!(stdout, Clear(ClearType::FromCursorDown))?;
+    Ok(())
+}
+
 async fn print_colored_text(
     text: &str,
     color: Color,
@@ -489,6 +1096,10 @@ async fn print_colored_text(
 ) -> std::io::Result<()> {
     let color_bool = color_bool.lock().await;
 
+    // \r\n so lines still start at the left edge while the input is in raw mode
+    let text = text.replace('\n', "\r\n");
+    let text = text.as_str();
+
     match *color_bool {
         true => {
             let mut stdout = std::io::stdout();
@@ -500,7 +1111,7 @@ async fn print_colored_text(
             execute!(stdout, Print(text))?;
 
             // \n for better readability
-            execute!(stdout, Print("\n"))?;
+            execute!(stdout, Print("\r\n"))?;
 
             // Reset the terminal color to default
             stdout.execute(ResetColor)?;
@@ -515,7 +1126,7 @@ async fn print_colored_text(
             execute!(stdout, Print(text))?;
 
             // \n for better readability
-            execute!(stdout, Print("\n"))?;
+            execute!(stdout, Print("\r\n"))?;
 
             // Clear incomplete commands or previous outputs
             execute!(stdout, Clear(ClearType::FromCursorDown))?;
@@ -525,21 +1136,23 @@ async fn print_colored_text(
     Ok(())
 }
 
-// Function to repeatedly attempt connecting to the server
+// Function to repeatedly attempt connecting to the server, doubling the delay after each failure
 async fn wait_for_server(
     ip: &str,
     port: u16,
+    connection_timeout: u64,
 ) -> Result<TcpStream, Box<dyn StdError + Send + Sync>> {
-    let address = format!("{}:{}", ip, port);
+    let address = format_address(ip, port);
 
     // Start time before the connection attempt
     let start_time = Instant::now();
+    let mut retry_delay = RETRY_DELAY;
 
     loop {
         println!("Attempting to connect to {}...", address);
 
         // Try to connect to the server
-        match TcpStream::connect(&address).await {
+        match TcpStream::connect((ip, port)).await {
             Ok(socket) => {
                 println!("Successfully connected to the server!");
                 return Ok(socket);
@@ -556,18 +1169,19 @@ async fn wait_for_server(
         let elapsed = start_time.elapsed();
         let elapsed_secs = elapsed.as_secs();
 
+        if elapsed_secs >= connection_timeout {
+            eprintln!("Connection timed out after {} seconds", connection_timeout);
+            return Err("Connection timed out".into());
+        }
+
         // Print the status and retry after a delay
         println!(
             "Retrying in {} second(s)... (Time elapsed: {} seconds)",
-            RETRY_DELAY, elapsed_secs
+            retry_delay, elapsed_secs
         );
 
-        if elapsed_secs >= CONNECTION_TIMEOUT {
-            eprintln!("Connection timed out after {} seconds", CONNECTION_TIMEOUT);
-            return Err("Connection timed out".into());
-        }
-
         // Wait for a bit before retrying
-        sleep(Duration::from_secs(RETRY_DELAY)).await;
+        sleep(Duration::from_secs(retry_delay)).await;
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
     }
 }
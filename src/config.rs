@@ -0,0 +1,577 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::net::IpAddr;
+use tokio::time::Duration;
+use toml::{Table, Value};
+
+use crate::discovery::DISCOVERY_PORT;
+use crate::logger::LogConfig;
+use crate::tools::{
+    parse_host, HeartbeatConfig, SlowClientPolicy, MAX_HEARTBEAT_INTERVAL_SECS,
+    MAX_HEARTBEAT_MISSED,
+};
+
+// Largest message text the server can be configured to accept, keeps frames well under the limit
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+// Upper bound for each client's outbound queue, in messages
+pub const MAX_OUTBOUND_QUEUE: usize = 4096;
+// Longest server name announced on the LAN
+pub const MAX_NAME_SIZE: usize = 64;
+// Longest mute for flooding, a week
+pub const MAX_MUTE_SECS: u64 = 7 * 24 * 60 * 60;
+
+// Server settings, read from a TOML file and `--set section.key=value` overrides
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServerConfig {
+    pub server: BindSection,
+    pub key: KeySection,
+    pub admin: AdminSection,
+    pub history: HistorySection,
+    pub limits: LimitsSection,
+    pub rate_limits: RateLimitsSection,
+    pub heartbeat: HeartbeatSection,
+    pub logging: LogConfig,
+    pub metrics: MetricsSection,
+    pub discovery: DiscoverySection,
+    pub offline: OfflineSection,
+}
+
+// Where to listen, both are asked interactively when missing
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct BindSection {
+    // One address or a list, all bound on the same port
+    #[serde(rename = "address", deserialize_with = "string_or_list")]
+    pub addresses: Vec<String>,
+    pub port: Option<u16>,
+    pub bind_policy: BindPolicy,
+    pub port_range_end: Option<u16>, // Last port tried with the range policy
+    pub port_file: Option<String>,   // Where to write the bound port, `-` for stdout
+    pub public_address: Option<String>, // Put in invites, the bound or local IP when missing
+}
+
+// What to do when the configured port is already taken
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BindPolicy {
+    #[default]
+    Fail, // Stop with an error
+    Range, // Try each port up to `port_range_end`, in order
+    Any,   // Ignore the port and let the system assign a free one
+}
+
+// AES key source: inline, from a file, or generated at startup when neither is set
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct KeySection {
+    pub value: Option<String>,
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct AdminSection {
+    pub sudo_enabled: bool,
+    #[serde(deserialize_with = "string_or_number")]
+    pub sudo_code: Option<String>, // Random 4 digit code when missing
+    pub require_invite: bool, // Only clients that joined with an invite get in, even with the key
+}
+
+impl Default for AdminSection {
+    fn default() -> Self {
+        AdminSection {
+            sudo_enabled: true,
+            sudo_code: None,
+            require_invite: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct HistorySection {
+    pub max_messages: usize, // Kept in the global history and in each user's messages
+}
+
+impl Default for HistorySection {
+    fn default() -> Self {
+        HistorySection {
+            max_messages: 10_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct LimitsSection {
+    pub max_clients: usize,
+    pub max_connections: usize, // Open sockets, including those still in the handshake
+    pub max_connections_per_ip: usize,
+    pub handshake_timeout_secs: u64,
+    pub max_message_size: usize, // In bytes of message text
+    pub outbound_queue: usize,   // Messages waiting to be sent to each client
+    pub slow_clients: SlowClientPolicy,
+}
+
+impl Default for LimitsSection {
+    fn default() -> Self {
+        LimitsSection {
+            max_clients: 100,
+            max_connections: 256,
+            max_connections_per_ip: 8,
+            handshake_timeout_secs: 10,
+            max_message_size: 4096,
+            outbound_queue: 64,
+            slow_clients: SlowClientPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct RateLimitsSection {
+    pub presence_cooldown_secs: u64, // Between /away, /back, /status and /nick
+    pub typing_cooldown_secs: u64,   // Between relayed typing notifications
+    pub accept_per_sec: f64,         // New connections accepted per second, on average
+    pub accept_burst: u32,           // New connections accepted at once before the rate applies
+    pub messages_per_sec: f64,       // Messages and commands each user may send, on average
+    pub message_burst: u32,
+    pub bytes_per_sec: f64, // Bytes of message text each user may send, on average
+    pub byte_burst: u32,
+    pub mute_after: u32, // Throttled messages in a row before the user is muted
+    pub mute_secs: u64,
+    pub exempt_sudo: bool, // Users with sudo privileges are never throttled
+}
+
+impl Default for RateLimitsSection {
+    fn default() -> Self {
+        RateLimitsSection {
+            presence_cooldown_secs: 3,
+            typing_cooldown_secs: 3,
+            accept_per_sec: 20.0,
+            accept_burst: 50,
+            messages_per_sec: 5.0,
+            message_burst: 10,
+            bytes_per_sec: 8192.0,
+            byte_burst: 16384,
+            mute_after: 20,
+            mute_secs: 60,
+            exempt_sudo: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct HeartbeatSection {
+    pub interval_secs: u64,
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatSection {
+    fn default() -> Self {
+        let heartbeat = HeartbeatConfig::default();
+        HeartbeatSection {
+            interval_secs: heartbeat.interval.as_secs(),
+            max_missed: heartbeat.max_missed,
+        }
+    }
+}
+
+// Prometheus endpoint, off by default and only reachable locally unless the address changes
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct MetricsSection {
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+}
+
+impl Default for MetricsSection {
+    fn default() -> Self {
+        MetricsSection {
+            enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 9100,
+        }
+    }
+}
+
+// LAN announcements, off by default, only the key fingerprint is announced
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct DiscoverySection {
+    pub enabled: bool,
+    pub name: String,    // Shown to clients looking for servers
+    pub address: String, // Where announcements are sent, the broadcast address by default
+    pub port: u16,       // UDP port clients listen on
+    pub interval_secs: u64,
+}
+
+impl Default for DiscoverySection {
+    fn default() -> Self {
+        DiscoverySection {
+            enabled: false,
+            name: "crypted-messages".to_string(),
+            address: "255.255.255.255".to_string(),
+            port: DISCOVERY_PORT,
+            interval_secs: 2,
+        }
+    }
+}
+
+// Direct messages and mentions held for users who connected before, until they come back
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct OfflineSection {
+    pub enabled: bool,
+    pub ttl_secs: u64, // How long messages are held, and users known after they last connected
+    pub max_per_user: usize, // The oldest are dropped first
+    pub file: Option<String>, // Keeps the queue across restarts, encrypted with the server key
+}
+
+impl Default for OfflineSection {
+    fn default() -> Self {
+        OfflineSection {
+            enabled: true,
+            ttl_secs: 7 * 24 * 60 * 60,
+            max_per_user: 100,
+            file: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Load the config file (if any), apply the overrides on top and validate the result
+    pub fn load(path: Option<&str>, overrides: &[String]) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => read_table(path)?,
+            None => Table::new(),
+        };
+        for assignment in overrides {
+            apply_override(&mut table, assignment)?;
+        }
+
+        let config: ServerConfig = table
+            .try_into()
+            .map_err(|e| anyhow!("Invalid server config: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for address in &self.server.addresses {
+            if parse_host(address).is_none() {
+                let problem = format!("`{}` is not an IP address or host name", address);
+                return Err(invalid("server.address", &problem));
+            }
+        }
+
+        if let Some(address) = &self.server.public_address {
+            if parse_host(address).is_none() {
+                let problem = format!("`{}` is not an IP address or host name", address);
+                return Err(invalid("server.public_address", &problem));
+            }
+        }
+
+        if self.server.bind_policy == BindPolicy::Range {
+            let Some(end) = self.server.port_range_end else {
+                return Err(invalid(
+                    "server.port_range_end",
+                    "must be set with the range bind policy",
+                ));
+            };
+            if self.server.port.is_some_and(|port| port == 0 || port > end) {
+                return Err(invalid(
+                    "server.port_range_end",
+                    "must be at least server.port, which can't be 0",
+                ));
+            }
+        }
+        if self.server.port_file.as_deref() == Some("") {
+            return Err(invalid("server.port_file", "must be a path or `-`"));
+        }
+
+        if self.metrics.address.parse::<IpAddr>().is_err() {
+            return Err(invalid("metrics.address", "expected an IP address"));
+        }
+
+        if self.discovery.address.parse::<IpAddr>().is_err() {
+            return Err(invalid("discovery.address", "expected an IP address"));
+        }
+        if self.discovery.name.trim().is_empty() || self.discovery.name.len() > MAX_NAME_SIZE {
+            let problem = format!("must be between 1 and {} bytes", MAX_NAME_SIZE);
+            return Err(invalid("discovery.name", &problem));
+        }
+        if self.discovery.port == 0 {
+            return Err(invalid("discovery.port", "must be at least 1"));
+        }
+        if self.discovery.interval_secs == 0 {
+            return Err(invalid("discovery.interval_secs", "must be at least 1"));
+        }
+
+        if self.offline.ttl_secs == 0 {
+            return Err(invalid("offline.ttl_secs", "must be at least 1"));
+        }
+        if self.offline.max_per_user == 0 {
+            return Err(invalid("offline.max_per_user", "must be at least 1"));
+        }
+        if self.offline.file.as_deref() == Some("") {
+            return Err(invalid("offline.file", "must be a path"));
+        }
+
+        if self.key.value.is_some() && self.key.file.is_some() {
+            return Err(invalid("key", "set either key.value or key.file, not both"));
+        }
+        if let Some(value) = &self.key.value {
+            check_key("key.value", value)?;
+        }
+
+        if let Some(code) = &self.admin.sudo_code {
+            if code.is_empty() || code.contains(char::is_whitespace) {
+                return Err(invalid("admin.sudo_code", "must be a single word"));
+            }
+        }
+        if self.admin.require_invite && !self.admin.sudo_enabled {
+            let problem = "needs admin.sudo_enabled, invites are created with sudo";
+            return Err(invalid("admin.require_invite", problem));
+        }
+
+        if self.history.max_messages == 0 {
+            return Err(invalid("history.max_messages", "must be at least 1"));
+        }
+        if self.limits.max_clients == 0 {
+            return Err(invalid("limits.max_clients", "must be at least 1"));
+        }
+        if self.limits.max_connections == 0 {
+            return Err(invalid("limits.max_connections", "must be at least 1"));
+        }
+        if self.limits.max_connections_per_ip == 0 {
+            return Err(invalid(
+                "limits.max_connections_per_ip",
+                "must be at least 1",
+            ));
+        }
+        if self.limits.handshake_timeout_secs == 0 {
+            return Err(invalid(
+                "limits.handshake_timeout_secs",
+                "must be at least 1",
+            ));
+        }
+        if !(1..=MAX_MESSAGE_SIZE).contains(&self.limits.max_message_size) {
+            let problem = format!("must be between 1 and {}", MAX_MESSAGE_SIZE);
+            return Err(invalid("limits.max_message_size", &problem));
+        }
+
+        if !(1..=MAX_OUTBOUND_QUEUE).contains(&self.limits.outbound_queue) {
+            let problem = format!("must be between 1 and {}", MAX_OUTBOUND_QUEUE);
+            return Err(invalid("limits.outbound_queue", &problem));
+        }
+
+        let accept_rate = self.rate_limits.accept_per_sec;
+        if accept_rate.is_nan() || accept_rate <= 0.0 {
+            return Err(invalid("rate_limits.accept_per_sec", "must be above 0"));
+        }
+        if self.rate_limits.accept_burst == 0 {
+            return Err(invalid("rate_limits.accept_burst", "must be at least 1"));
+        }
+        for (key, rate) in [
+            (
+                "rate_limits.messages_per_sec",
+                self.rate_limits.messages_per_sec,
+            ),
+            ("rate_limits.bytes_per_sec", self.rate_limits.bytes_per_sec),
+        ] {
+            if rate.is_nan() || rate <= 0.0 {
+                return Err(invalid(key, "must be above 0"));
+            }
+        }
+        if self.rate_limits.message_burst == 0 {
+            return Err(invalid("rate_limits.message_burst", "must be at least 1"));
+        }
+        // Otherwise the longest allowed messages could never be sent
+        if (self.rate_limits.byte_burst as usize) < self.limits.max_message_size {
+            return Err(invalid(
+                "rate_limits.byte_burst",
+                "must be at least limits.max_message_size",
+            ));
+        }
+        if self.rate_limits.mute_after == 0 {
+            return Err(invalid("rate_limits.mute_after", "must be at least 1"));
+        }
+        if self.rate_limits.mute_secs > MAX_MUTE_SECS {
+            let problem = format!("must be at most {}", MAX_MUTE_SECS);
+            return Err(invalid("rate_limits.mute_secs", &problem));
+        }
+
+        if !(1..=MAX_HEARTBEAT_INTERVAL_SECS).contains(&self.heartbeat.interval_secs) {
+            let problem = format!("must be between 1 and {}", MAX_HEARTBEAT_INTERVAL_SECS);
+            return Err(invalid("heartbeat.interval_secs", &problem));
+        }
+        if !(1..=MAX_HEARTBEAT_MISSED).contains(&self.heartbeat.max_missed) {
+            let problem = format!("must be between 1 and {}", MAX_HEARTBEAT_MISSED);
+            return Err(invalid("heartbeat.max_missed", &problem));
+        }
+        Ok(())
+    }
+
+    // The AES key from the config, None when the server should generate one
+    pub fn resolve_key(&self) -> Result<Option<String>> {
+        match (&self.key.value, &self.key.file) {
+            (Some(value), _) => Ok(Some(value.clone())),
+            (None, Some(file)) => {
+                let key = fs::read_to_string(file)
+                    .with_context(|| format!("Failed to read key.file `{}`", file))?;
+                let key = key.trim().to_string();
+                check_key("key.file", &key)?;
+                Ok(Some(key))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(self.heartbeat.interval_secs),
+            max_missed: self.heartbeat.max_missed,
+        }
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.limits.handshake_timeout_secs)
+    }
+
+    pub fn presence_cooldown(&self) -> Duration {
+        Duration::from_secs(self.rate_limits.presence_cooldown_secs)
+    }
+
+    pub fn typing_cooldown(&self) -> Duration {
+        Duration::from_secs(self.rate_limits.typing_cooldown_secs)
+    }
+
+    pub fn mute_duration(&self) -> Duration {
+        Duration::from_secs(self.rate_limits.mute_secs)
+    }
+}
+
+fn invalid(key: &str, problem: &str) -> anyhow::Error {
+    anyhow!("Invalid config value `{}`: {}", key, problem)
+}
+
+// Keys are 32 bytes written as hexadecimal
+fn check_key(config_key: &str, key: &str) -> Result<()> {
+    if key.len() != 64 || hex::decode(key).is_err() {
+        return Err(invalid(config_key, "expected 64 hexadecimal characters"));
+    }
+    Ok(())
+}
+
+fn read_table(path: &str) -> Result<Table> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read config `{}`", path))?;
+    contents
+        .parse::<Table>()
+        .map_err(|e| anyhow!("Failed to parse config `{}`: {}", path, e))
+}
+
+// Applies a `section.key=value` override, the value is read as TOML and falls back to a string
+fn apply_override(table: &mut Table, assignment: &str) -> Result<()> {
+    let (path, raw_value) = assignment
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid override `{}`, expected key=value", assignment))?;
+
+    let raw_value = raw_value.trim();
+    let value = format!("value = {}", raw_value)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| Value::String(raw_value.to_string()));
+
+    let mut keys: Vec<&str> = path.trim().split('.').collect();
+    let last = keys.pop().unwrap_or_default();
+    let mut section = table;
+    for key in keys {
+        section = section
+            .entry(key)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Invalid override `{}`: `{}` is not a section", path, key))?;
+    }
+    section.insert(last.to_string(), value);
+    Ok(())
+}
+
+// Codes like 1234 are read as numbers by TOML, accept both forms
+fn string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(i64),
+    }
+
+    Ok(
+        Option::<StringOrNumber>::deserialize(deserializer)?.map(|value| match value {
+            StringOrNumber::String(value) => value,
+            StringOrNumber::Number(value) => value.to_string(),
+        }),
+    )
+}
+
+// A single address is the same as a list with one entry
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(value) => vec![value],
+        StringOrList::List(values) => values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let overrides = vec![
+            "limits.max_clients=5".to_string(),
+            "admin.sudo_code=1234".to_string(),
+            "server.address=[\"127.0.0.1\", \"[::1]\"]".to_string(),
+        ];
+        let config = ServerConfig::load(None, &overrides).unwrap();
+        assert_eq!(config.limits.max_clients, 5);
+        assert_eq!(config.admin.sudo_code.as_deref(), Some("1234"));
+        assert_eq!(config.server.addresses, ["127.0.0.1", "[::1]"]);
+        assert_eq!(config.history.max_messages, 10_000);
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let error = |assignment: &str| {
+            ServerConfig::load(None, &[assignment.to_string()])
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("limits.max_clients=0").contains("limits.max_clients"));
+        assert!(error("limits.max_clients=lots").contains("limits.max_clients"));
+        assert!(error("key.value=abc").contains("key.value"));
+        assert!(error("server.address=not an address").contains("server.address"));
+        assert!(error("server.bind_policy=range").contains("server.port_range_end"));
+        assert!(error("server.bind_policy=hop").contains("server.bind_policy"));
+        assert!(error("limits.max_clientz=3").contains("max_clientz"));
+        let huge = format!("heartbeat.interval_secs={}", u64::MAX);
+        assert!(error(&huge).contains("heartbeat.interval_secs"));
+        let huge = format!("rate_limits.mute_secs={}", u64::MAX);
+        assert!(error(&huge).contains("rate_limits.mute_secs"));
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{interval, sleep_until, Duration, Instant};
+
+use crate::config::DiscoverySection;
+use crate::logger::{debug, info, warning};
+
+// UDP port announcements go to unless the server config says otherwise
+pub const DISCOVERY_PORT: u16 = 5556;
+// How long `client --discover` listens, a few announcement intervals
+pub const DISCOVERY_WAIT: Duration = Duration::from_secs(5);
+// Tells our announcements apart from anything else sent to the port
+const ANNOUNCEMENT_TAG: &str = "crypted-messages";
+// Announcements are small, anything bigger isn't ours
+const MAX_ANNOUNCEMENT_SIZE: usize = 1024;
+
+// What a server sends out, the key fingerprint lets users check they picked the right one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub app: String,
+    pub name: String,
+    pub port: u16,
+    pub fingerprint: String,
+}
+
+// A server heard from, at the address the announcement came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    pub address: IpAddr,
+    pub port: u16,
+    pub name: String,
+    pub fingerprint: String,
+}
+
+impl Announcement {
+    pub fn new(name: String, port: u16, fingerprint: String) -> Self {
+        Announcement {
+            app: ANNOUNCEMENT_TAG.to_string(),
+            name,
+            port,
+            fingerprint,
+        }
+    }
+
+    // None for packets that aren't announcements
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let announcement: Announcement = serde_json::from_slice(packet).ok()?;
+        (announcement.app == ANNOUNCEMENT_TAG && announcement.port != 0).then_some(announcement)
+    }
+}
+
+// Sends an announcement every interval until the server stops
+pub async fn announce(config: DiscoverySection, announcement: Announcement) {
+    let socket = match announce_socket().await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warning!("LAN discovery disabled, failed to open a UDP socket: {}", e);
+            return;
+        }
+    };
+    let packet = match serde_json::to_vec(&announcement) {
+        Ok(packet) => packet,
+        Err(e) => {
+            warning!(
+                "LAN discovery disabled, failed to encode the announcement: {}",
+                e
+            );
+            return;
+        }
+    };
+    let target = (config.address.as_str(), config.port);
+    info!(
+        "Announcing `{}` to {}:{} every {}s",
+        announcement.name, config.address, config.port, config.interval_secs
+    );
+
+    let mut ticker = interval(Duration::from_secs(config.interval_secs));
+    let mut failing = false;
+    loop {
+        ticker.tick().await;
+        // Only the first failure in a row is worth a warning, the network may come back
+        match socket.send_to(&packet, target).await {
+            Ok(_) => failing = false,
+            Err(e) if !failing => {
+                warning!("Failed to send the LAN announcement: {}", e);
+                failing = true;
+            }
+            Err(e) => debug!("Failed to send the LAN announcement: {}", e),
+        }
+    }
+}
+
+async fn announce_socket() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    Ok(socket)
+}
+
+// Listens for announcements on `port`, one entry per server however often it was heard
+pub async fn discover(port: u16, wait: Duration) -> Result<Vec<DiscoveredServer>> {
+    let socket = listen_socket(port)
+        .with_context(|| format!("Failed to listen for servers on UDP port {}", port))?;
+    let deadline = Instant::now() + wait;
+    let mut servers = BTreeMap::new();
+    let mut packet = [0u8; MAX_ANNOUNCEMENT_SIZE];
+    loop {
+        let (size, from) = tokio::select! {
+            received = socket.recv_from(&mut packet) => received?,
+            _ = sleep_until(deadline) => break,
+        };
+        if let Some(announcement) = Announcement::parse(&packet[..size]) {
+            let server = DiscoveredServer {
+                address: from.ip(),
+                port: announcement.port,
+                name: announcement.name,
+                fingerprint: announcement.fingerprint,
+            };
+            servers.insert((server.address, server.port), server);
+        }
+    }
+    Ok(servers.into_values().collect())
+}
+
+// Several clients on one machine can look for servers at the same time
+fn listen_socket(port: u16) -> io::Result<UdpSocket> {
+    let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_announcements() {
+        let announcement = Announcement::new("office".into(), 5555, "ab12:cd34".into());
+        let packet = serde_json::to_vec(&announcement).unwrap();
+        assert_eq!(Announcement::parse(&packet), Some(announcement));
+        assert_eq!(Announcement::parse(b"hello"), None);
+        assert_eq!(
+            Announcement::parse(br#"{"app":"other","name":"x","port":1,"fingerprint":""}"#),
+            None
+        );
+
+        // Over loopback, the same server heard twice is listed once
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listener = tokio::spawn(discover(port, Duration::from_millis(500)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..2 {
+            sender.send_to(&packet, ("127.0.0.1", port)).await.unwrap();
+        }
+        let servers = listener.await.unwrap().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].address, IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_eq!(servers[0].port, 5555);
+        assert_eq!(servers[0].name, "office");
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+use base64ct::{Base64UrlUnpadded, Encoding};
+
+use crate::tools::{format_address, key_digest, parse_host};
+
+// Invites look like `cm://host:port#payload`, the payload never reaches a server in a URL
+const INVITE_SCHEME: &str = "cm://";
+const KEY_SIZE: usize = 32;
+pub const INVITE_ID_SIZE: usize = 16;
+const DIGEST_SIZE: usize = 8;
+
+// Everything needed to join a server, handed out with /invite
+// The payload holds the key, the invite ID the server checks, and the key fingerprint to catch typos
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub host: String,
+    pub port: u16,
+    pub key: String, // Hexadecimal, like everywhere else
+    pub id: String,  // Hexadecimal
+}
+
+impl Invite {
+    pub fn to_token(&self) -> Result<String> {
+        let mut payload = hex::decode(&self.key)?;
+        payload.extend(hex::decode(&self.id)?);
+        payload.extend(key_digest(&self.key));
+        if payload.len() != KEY_SIZE + INVITE_ID_SIZE + DIGEST_SIZE {
+            return Err(anyhow!("Invalid key or invite ID size"));
+        }
+        Ok(format!(
+            "{}{}#{}",
+            INVITE_SCHEME,
+            format_address(&self.host, self.port),
+            Base64UrlUnpadded::encode_string(&payload)
+        ))
+    }
+
+    pub fn parse(token: &str) -> Result<Self> {
+        let invalid = |problem: &str| anyhow!("Invalid invite: {}", problem);
+        let rest = token
+            .trim()
+            .strip_prefix(INVITE_SCHEME)
+            .ok_or_else(|| invalid("it should start with cm://"))?;
+        let (address, payload) = rest
+            .split_once('#')
+            .ok_or_else(|| invalid("the key is missing"))?;
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("the port is missing"))?;
+        let host = parse_host(host).ok_or_else(|| invalid("bad address"))?;
+        let port = port
+            .parse()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| invalid("bad port"))?;
+
+        let payload =
+            Base64UrlUnpadded::decode_vec(payload).map_err(|_| invalid("the key is damaged"))?;
+        if payload.len() != KEY_SIZE + INVITE_ID_SIZE + DIGEST_SIZE {
+            return Err(invalid("the key is damaged"));
+        }
+        let (key, rest) = payload.split_at(KEY_SIZE);
+        let (id, digest) = rest.split_at(INVITE_ID_SIZE);
+        let key = hex::encode(key);
+        if key_digest(&key) != digest {
+            return Err(invalid("the key doesn't match its fingerprint"));
+        }
+        Ok(Invite {
+            host,
+            port,
+            key,
+            id: hex::encode(id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invites() {
+        let invite = Invite {
+            host: "::1".to_string(),
+            port: 5555,
+            key: "ab".repeat(KEY_SIZE),
+            id: "cd".repeat(INVITE_ID_SIZE),
+        };
+        let token = invite.to_token().unwrap();
+        assert!(token.starts_with("cm://[::1]:5555#"));
+        assert_eq!(Invite::parse(&token).unwrap(), invite);
+
+        // A changed character breaks the fingerprint
+        let mut damaged = token.clone();
+        damaged.replace_range(20..21, if &token[20..21] == "A" { "B" } else { "A" });
+        assert!(Invite::parse(&damaged).is_err());
+
+        assert!(Invite::parse("http://host:5555#abc").is_err());
+        assert!(Invite::parse("cm://host#abc").is_err());
+        assert!(Invite::parse(&token.replace(":5555", ":0")).is_err());
+    }
+}
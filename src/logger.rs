@@ -0,0 +1,266 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::tools::get_timestamp;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug, // Also reveals values wrapped in `sensitive`
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+}
+
+// Where and how much to log, the `[logging]` section of the server config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct LogConfig {
+    pub level: Level,
+    pub targets: BTreeMap<String, Level>, // Per module levels, e.g. `server = "debug"`
+    pub format: Format,
+    pub console: bool, // Written to stderr
+    pub file: Option<PathBuf>,
+    pub max_file_size: u64, // In bytes, the file is rotated once it would grow past this
+    pub max_files: usize,   // Rotated files kept next to the current one
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Level::Info,
+            targets: BTreeMap::new(),
+            format: Format::Text,
+            console: true,
+            file: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+struct Logger {
+    config: LogConfig,
+    file: Option<Mutex<LogFile>>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+// Installs the logger, only the first call has any effect
+pub fn init(config: LogConfig) -> Result<()> {
+    let file = match &config.file {
+        Some(path) => Some(Mutex::new(LogFile::open(path)?)),
+        None => None,
+    };
+    let _ = LOGGER.set(Logger { config, file });
+    Ok(())
+}
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        config: LogConfig::default(),
+        file: None,
+    })
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    let config = &logger().config;
+    level <= *config.targets.get(target).unwrap_or(&config.level)
+}
+
+// Called through the macros below, the target is the module the call comes from
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    let target = module.rsplit("::").next().unwrap_or(module);
+    if !enabled(level, target) {
+        return;
+    }
+
+    let logger = logger();
+    let line = match logger.config.format {
+        Format::Text => format!(
+            "{} {:<5} {}: {}",
+            get_timestamp(),
+            level.as_str().to_uppercase(),
+            target,
+            args
+        ),
+        Format::Json => serde_json::json!({
+            "time": get_timestamp(),
+            "level": level.as_str(),
+            "target": target,
+            "message": args.to_string(),
+        })
+        .to_string(),
+    };
+
+    if logger.config.console {
+        eprintln!("{}", line);
+    }
+    if let Some(file) = &logger.file {
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_line(&line, &logger.config) {
+            eprintln!("Failed to write log file: {}", e);
+        }
+    }
+}
+
+// Message contents and keys are only written when debug logging is enabled
+pub struct Sensitive<T>(T);
+
+pub fn sensitive<T>(value: T) -> Sensitive<T> {
+    Sensitive(value)
+}
+
+impl<T: fmt::Debug> fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match logger().config.level {
+            Level::Debug => self.0.fmt(f),
+            _ => f.write_str("[redacted]"),
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match logger().config.level {
+            Level::Debug => self.0.fmt(f),
+            _ => f.write_str("[redacted]"),
+        }
+    }
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create log directory `{}`", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file `{}`", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str, config: &LogConfig) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > config.max_file_size {
+            self.rotate(config.max_files)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    // server.log -> server.log.1 -> server.log.2 ..., the oldest one is dropped
+    fn rotate(&mut self, max_files: usize) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(max_files));
+            for n in (1..max_files).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Error, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Warn, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Info, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Debug, module_path!(), format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("crypted-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("server.log");
+        let config = LogConfig {
+            max_file_size: 20,
+            max_files: 2,
+            ..LogConfig::default()
+        };
+
+        let mut file = LogFile::open(&path).unwrap();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line, &config).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "fourth line\n");
+        assert_eq!(read("server.log.1"), "third line\n");
+        assert_eq!(read("server.log.2"), "second line\n");
+        assert!(!dir.join("server.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
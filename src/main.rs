@@ -1,18 +1,245 @@
+mod bench;
 mod client;
+mod config;
+mod discovery;
+mod invite;
+mod logger;
+mod metrics;
+mod offline;
+mod profiles;
 mod server;
 mod tools;
+use bench::{BenchConfig, MAX_RATE};
+use config::ServerConfig;
+use discovery::DISCOVERY_PORT;
+use invite::Invite;
 use local_ip_address::local_ip;
+use profiles::{ClientSettings, ProfilesFile};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::{ self };
-use tools::get_user_input;
+use tools::{
+    get_user_input, key_fingerprint, parse_host, MAX_HEARTBEAT_INTERVAL_SECS, MAX_HEARTBEAT_MISSED,
+};
+
+const USAGE: &str = "Usage:
+  crypted-messages                      Interactive menu
+  crypted-messages server [options]     Start the server directly
+    --config <path>                     Read settings from a TOML file
+    --set <section.key=value>           Override a setting, can be repeated
+  crypted-messages client [options]     Start the client directly
+    --profile <name>                    Connect with a saved profile
+    --save-profile <name>               Create or replace a profile, then exit
+    --profiles <path>                   Profiles file (default ~/.crypted-messages/profiles.toml)
+    --discover                          Pick a server announcing itself on the local network
+    --discover-port <port>              UDP port to listen on for announcements (default 5556)
+    --invite <cm://...>                 Join with an invite from /invite
+    --notify <command>                  Run a command when mentioned instead of ringing the bell
+    --heartbeat-interval <secs>         Seconds between pings to the server, up to 3600 (default 15)
+    --heartbeat-missed <n>              Unanswered pings before reconnecting, up to 100 (default 3)
+  crypted-messages bench [options]      Load test a running server with simulated clients
+    --port <port>                       Server port (required)
+    --key <hex>                         Server key (required)
+    --address <host>                    Server address or host name (default 127.0.0.1)
+    --clients <n>                       Simulated clients (default 10)
+    --rate <n>                          Messages per second sent by each client, up to 1000 (default 1)
+    --duration <secs>                   How long the clients send for (default 10)
+    --size <bytes>                      Size of each message (default 64)";
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = run_cli(&args).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Welcome to the chat application!");
     if let Err(e) = run().await {
         eprintln!("Application error: {:?}", e);
     }
 }
 
+// Non-interactive entry point
+async fn run_cli(args: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match args[0].as_str() {
+        "server" => {
+            let (path, overrides) = parse_server_args(&args[1..])?;
+            let config = ServerConfig::load(path.as_deref(), &overrides)?;
+            logger::init(config.logging.clone())?;
+            server::main_server(config).await
+        }
+        "client" => {
+            let args = parse_client_args(&args[1..])?;
+            let path = args.profiles.unwrap_or_else(profiles::default_path);
+            if let Some(name) = args.save_profile {
+                return Ok(profiles::save_profile_interactive(&path, &name)?);
+            }
+            let mut settings = match args.profile {
+                Some(name) => ProfilesFile::load(&path)?.settings(&name)?,
+                None => ClientSettings::default(),
+            };
+            if args.discover {
+                let port = args.discover_port.unwrap_or(DISCOVERY_PORT);
+                let server = client::choose_server(port).await?;
+                settings.address = Some(server.address.to_string());
+                settings.port = Some(server.port);
+                settings.fingerprint = Some(server.fingerprint);
+            }
+            if let Some(token) = args.invite {
+                let invite = Invite::parse(&token)?;
+                settings.fingerprint = Some(key_fingerprint(&invite.key));
+                settings.address = Some(invite.host);
+                settings.port = Some(invite.port);
+                settings.key = Some(invite.key);
+                settings.invite = Some(invite.id);
+            }
+            if let Some(command) = args.notify {
+                settings.notify = Some(command);
+            }
+            if let Some(interval) = args.heartbeat_interval {
+                settings.heartbeat.interval = Duration::from_secs(interval);
+            }
+            if let Some(max_missed) = args.heartbeat_missed {
+                settings.heartbeat.max_missed = max_missed;
+            }
+            client::main_client(settings).await
+        }
+        "bench" => {
+            let config = parse_bench_args(&args[1..])?;
+            Ok(bench::run(config).await?)
+        }
+        "--help" | "-h" | "help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(format!("Unknown command `{}`\n{}", other, USAGE).into()),
+    }
+}
+
+// Splits `--config <path>` and `--set key=value` arguments
+fn parse_server_args(
+    args: &[String],
+) -> Result<(Option<String>, Vec<String>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut path = None;
+    let mut overrides = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(value) => path = Some(value.clone()),
+                None => return Err(format!("--config needs a path\n{}", USAGE).into()),
+            },
+            "--set" => match args.next() {
+                Some(value) => overrides.push(value.clone()),
+                None => return Err(format!("--set needs section.key=value\n{}", USAGE).into()),
+            },
+            other => return Err(format!("Unknown option `{}`\n{}", other, USAGE).into()),
+        }
+    }
+    Ok((path, overrides))
+}
+
+#[derive(Default)]
+struct ClientArgs {
+    profile: Option<String>,
+    save_profile: Option<String>,
+    profiles: Option<PathBuf>,
+    discover: bool,
+    discover_port: Option<u16>,
+    invite: Option<String>,
+    notify: Option<String>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_missed: Option<u32>,
+}
+
+fn parse_client_args(
+    args: &[String],
+) -> Result<ClientArgs, Box<dyn std::error::Error + Send + Sync>> {
+    let mut parsed = ClientArgs::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--discover" {
+            parsed.discover = true;
+            continue;
+        }
+        let Some(value) = args.next() else {
+            return Err(format!("{} needs a value\n{}", arg, USAGE).into());
+        };
+        match arg.as_str() {
+            "--profile" => parsed.profile = Some(value.clone()),
+            "--discover-port" => {
+                let port = value.parse().ok().filter(|port| *port != 0);
+                let port = port.ok_or_else(|| format!("Invalid value `{}` for {}", value, arg))?;
+                parsed.discover_port = Some(port);
+            }
+            "--invite" => parsed.invite = Some(value.clone()),
+            "--notify" => parsed.notify = Some(value.clone()),
+            "--heartbeat-interval" => {
+                let interval = value
+                    .parse()
+                    .ok()
+                    .filter(|interval| (1..=MAX_HEARTBEAT_INTERVAL_SECS).contains(interval));
+                let interval =
+                    interval.ok_or_else(|| format!("Invalid value `{}` for {}", value, arg))?;
+                parsed.heartbeat_interval = Some(interval);
+            }
+            "--heartbeat-missed" => {
+                let missed = value
+                    .parse()
+                    .ok()
+                    .filter(|missed| (1..=MAX_HEARTBEAT_MISSED).contains(missed));
+                let missed = missed.ok_or_else(|| format!("Invalid value `{}` for {}", value, arg))?;
+                parsed.heartbeat_missed = Some(missed);
+            }
+            "--save-profile" => parsed.save_profile = Some(value.clone()),
+            "--profiles" => parsed.profiles = Some(PathBuf::from(value)),
+            other => return Err(format!("Unknown option `{}`\n{}", other, USAGE).into()),
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_bench_args(
+    args: &[String],
+) -> Result<BenchConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = BenchConfig::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(value) = args.next() else {
+            return Err(format!("{} needs a value\n{}", arg, USAGE).into());
+        };
+        let invalid = || format!("Invalid value `{}` for {}", value, arg);
+        match arg.as_str() {
+            "--address" => config.address = parse_host(value).ok_or_else(invalid)?,
+            "--port" => config.port = value.parse().map_err(|_| invalid())?,
+            "--key" => config.key = value.clone(),
+            "--clients" => config.clients = value.parse().map_err(|_| invalid())?,
+            "--rate" => {
+                let rate: f64 = value.parse().map_err(|_| invalid())?;
+                if !(rate > 0.0 && rate <= MAX_RATE) {
+                    return Err(invalid().into());
+                }
+                config.rate = rate;
+            }
+            "--duration" => {
+                let seconds: f64 = value.parse().map_err(|_| invalid())?;
+                config.duration = Duration::try_from_secs_f64(seconds).map_err(|_| invalid())?;
+            }
+            "--size" => config.size = value.parse().map_err(|_| invalid())?,
+            other => return Err(format!("Unknown option `{}`\n{}", other, USAGE).into()),
+        }
+    }
+    if config.port == 0 || config.key.is_empty() {
+        return Err(format!("bench needs --port and --key\n{}", USAGE).into());
+    }
+    Ok(config)
+}
+
 // Main menu
 async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     loop {
@@ -49,7 +276,7 @@ async fn start_server_flow() -> Result<(), Box<dyn std::error::Error + Send + Sy
     println!("Starting server...");
 
     // Start the server
-    server::main_server(None).await?;
+    server::main_server(ServerConfig::default()).await?;
     println!("Server stopped. Returning to the main menu...");
 
     Ok(())
@@ -57,7 +284,7 @@ async fn start_server_flow() -> Result<(), Box<dyn std::error::Error + Send + Sy
 
 // Start the client
 async fn start_client() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    match client::main_client().await {
+    match client::main_client(ClientSettings::default()).await {
         Ok(_) => println!("Client session ended. Returning to the main menu..."),
         Err(err) => {
             return Err(err);
@@ -72,4 +299,5 @@ fn show_ip_address() {
         Ok(ip) => println!("This is my local IP address: {:?}", ip),
         Err(e) => println!("Error getting local IP: {:?}", e),
     }
+    println!("Servers with LAN discovery on can be found with `crypted-messages client --discover`");
 }
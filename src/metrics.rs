@@ -0,0 +1,328 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::logger::{info, warning};
+
+// Messages per second are averaged over this window
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub static METRICS: Metrics = Metrics::new();
+
+// Why a connection was dropped by the accept loop, the `reason` label of the rejections counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    MaxConnections,
+    PerIp,
+    AcceptRate,
+}
+
+impl Rejection {
+    const ALL: [Rejection; 3] = [
+        Rejection::MaxConnections,
+        Rejection::PerIp,
+        Rejection::AcceptRate,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::MaxConnections => "max_connections",
+            Rejection::PerIp => "per_ip",
+            Rejection::AcceptRate => "accept_rate",
+        }
+    }
+}
+
+// Server counters and gauges, rendered in the Prometheus text format
+pub struct Metrics {
+    connected_clients: AtomicU64,
+    connections: AtomicU64,
+    messages: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    decrypt_failures: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    broadcast_lagged: AtomicU64,
+    throttled: AtomicU64,
+    mutes: AtomicU64,
+    rejections: [AtomicU64; Rejection::ALL.len()],
+    rate: Mutex<RateWindow>,
+}
+
+struct RateWindow {
+    start: Option<Instant>,
+    count: u64,
+    rate: f64, // Rate of the last complete window
+}
+
+impl RateWindow {
+    fn roll(&mut self, now: Instant) {
+        let start = *self.start.get_or_insert(now);
+        let elapsed = now - start;
+        if elapsed >= RATE_WINDOW {
+            self.rate = self.count as f64 / elapsed.as_secs_f64();
+            self.count = 0;
+            self.start = Some(now);
+        }
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            connected_clients: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            messages: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            decrypt_failures: AtomicU64::new(0),
+            handshake_timeouts: AtomicU64::new(0),
+            broadcast_lagged: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            mutes: AtomicU64::new(0),
+            rejections: [const { AtomicU64::new(0) }; Rejection::ALL.len()],
+            rate: Mutex::new(RateWindow {
+                start: None,
+                count: 0,
+                rate: 0.0,
+            }),
+        }
+    }
+
+    pub fn client_connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        let mut rate = self.rate.lock().unwrap_or_else(|e| e.into_inner());
+        rate.roll(Instant::now());
+        rate.count += 1;
+    }
+
+    pub fn bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn decrypt_failure(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_timeout(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn broadcast_lagged(&self, dropped: u64) {
+        self.broadcast_lagged.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    pub fn message_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn user_muted(&self) {
+        self.mutes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self, rejection: Rejection) {
+        self.rejections[rejection as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn messages_per_second(&self) -> f64 {
+        let mut rate = self.rate.lock().unwrap_or_else(|e| e.into_inner());
+        rate.roll(Instant::now());
+        rate.rate
+    }
+
+    pub fn render(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed).to_string();
+        let metrics = [
+            (
+                "connected_clients",
+                "gauge",
+                "Clients currently connected",
+                load(&self.connected_clients),
+            ),
+            (
+                "connections_total",
+                "counter",
+                "Clients accepted since startup",
+                load(&self.connections),
+            ),
+            (
+                "messages_total",
+                "counter",
+                "Chat messages received",
+                load(&self.messages),
+            ),
+            (
+                "messages_per_second",
+                "gauge",
+                "Chat messages per second over the last 10 seconds",
+                format!("{:.2}", self.messages_per_second()),
+            ),
+            (
+                "bytes_received_total",
+                "counter",
+                "Bytes read from clients, frame headers included",
+                load(&self.bytes_in),
+            ),
+            (
+                "bytes_sent_total",
+                "counter",
+                "Bytes written to clients, frame headers included",
+                load(&self.bytes_out),
+            ),
+            (
+                "decrypt_failures_total",
+                "counter",
+                "Frames that could not be decrypted",
+                load(&self.decrypt_failures),
+            ),
+            (
+                "handshake_timeouts_total",
+                "counter",
+                "Connections that never completed the handshake",
+                load(&self.handshake_timeouts),
+            ),
+            (
+                "broadcast_lagged_total",
+                "counter",
+                "Messages dropped for clients that fell behind the broadcast channel",
+                load(&self.broadcast_lagged),
+            ),
+            (
+                "messages_throttled_total",
+                "counter",
+                "Messages refused by the per user rate limits",
+                load(&self.throttled),
+            ),
+            (
+                "mutes_total",
+                "counter",
+                "Users muted for flooding",
+                load(&self.mutes),
+            ),
+            // There is a single global chat for now
+            ("rooms", "gauge", "Chat rooms", "1".to_string()),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(output, "# HELP crypted_{} {}", name, help);
+            let _ = writeln!(output, "# TYPE crypted_{} {}", name, kind);
+            let _ = writeln!(output, "crypted_{} {}", name, value);
+        }
+
+        let name = "crypted_connections_rejected_total";
+        let help = "Connections dropped by the accept loop, by the limit they hit";
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for rejection in Rejection::ALL {
+            let count = self.rejections[rejection as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                output,
+                "{}{{reason=\"{}\"}} {}",
+                name,
+                rejection.as_str(),
+                count
+            );
+        }
+        output
+    }
+}
+
+// Answers `GET /metrics` on the listener, anything else gets a 404
+pub async fn serve(listener: TcpListener) {
+    if let Ok(address) = listener.local_addr() {
+        info!("Metrics available at http://{}/metrics", address);
+    }
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = handle_request(socket).await {
+                        warning!("Failed to answer metrics request: {}", e);
+                    }
+                });
+            }
+            Err(e) => warning!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+async fn handle_request(mut socket: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = match timeout(REQUEST_TIMEOUT, socket.read(&mut buffer)).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(()),
+        };
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = METRICS.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+        METRICS.message();
+
+        let get = |path: &'static str| async move {
+            let mut socket = TcpStream::connect(address).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            socket.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("# TYPE crypted_messages_total counter"));
+        assert!(response.contains("crypted_connected_clients "));
+        assert!(!response.contains("crypted_messages_total 0\n"));
+        assert!(response.contains("crypted_connections_rejected_total{reason=\"per_ip\"} "));
+
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
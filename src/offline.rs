@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::OfflineSection;
+use crate::logger::{info, warning};
+use crate::tools::{decrypt, encrypt, encrypt_message, Message};
+
+// Messages held for users while they are offline, direct messages and mentions
+// Every message is kept encrypted with the server key, and so is the file the queue is saved to
+pub struct OfflineQueue {
+    config: OfflineSection,
+    key: String,
+    store: Mutex<Store>,
+    // Saves run one at a time, they share the partial file and the newest snapshot is written last
+    saving: tokio::sync::Mutex<()>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Store {
+    // Who connected within the TTL, by name
+    known: HashMap<String, Known>,
+    queues: HashMap<String, VecDeque<Queued>>,
+}
+
+// A name belongs to the identity that last used it until the TTL runs out
+#[derive(Serialize, Deserialize, Clone)]
+struct Known {
+    connected: u64, // Seconds since the Unix epoch
+    author: String, // Fingerprint of the client identity
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Queued {
+    queued_at: u64,
+    pub frame: Vec<u8>, // The encrypted message, as sent on the wire
+    // Fingerprint of the author's identity, which the frame doesn't carry, for receipts
+    #[serde(default)]
+    pub author: Option<String>,
+}
+
+impl OfflineQueue {
+    // Starts from the saved queue when there is one, or empty when it can't be read
+    pub fn load(config: &OfflineSection, key: &str) -> Self {
+        let store = match &config.file {
+            Some(path) if config.enabled => read_store(path, key).unwrap_or_else(|e| {
+                warning!("Starting with an empty offline queue: {:#}", e);
+                Store::default()
+            }),
+            _ => Store::default(),
+        };
+        OfflineQueue {
+            config: config.clone(),
+            key: key.to_string(),
+            store: Mutex::new(store),
+            saving: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // Remembers that `name` connected with the identity `author`, messages for them are queued
+    // from now on. False when the name still belongs to another identity
+    pub fn remember(&self, name: &str, author: &str) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let owned = store
+            .known
+            .get(name)
+            .is_some_and(|known| known.author != author && !self.expired(known.connected, now));
+        if owned {
+            return false;
+        }
+        let known = Known {
+            connected: now,
+            author: author.to_string(),
+        };
+        if store
+            .known
+            .insert(name.to_string(), known)
+            .is_some_and(|old| old.author != author)
+        {
+            // What was held for the previous owner expired with them
+            store.queues.remove(name);
+        }
+        true
+    }
+
+    // Fingerprint of the identity `name` belongs to, when messages for them would be queued
+    pub fn owner(&self, name: &str) -> Option<String> {
+        if !self.config.enabled {
+            return None;
+        }
+        let store = self.store.lock().unwrap();
+        let known = store.known.get(name)?;
+        (!self.expired(known.connected, now())).then(|| known.author.clone())
+    }
+
+    // Queues a message for a known user, false when `name` isn't one
+    // Direct messages are only held for the identity they were sent to
+    pub fn push(&self, name: &str, msg: &Message) -> Result<bool> {
+        let Some(owner) = self.owner(name) else {
+            return Ok(false);
+        };
+        if msg
+            .recipient
+            .as_ref()
+            .is_some_and(|recipient| *recipient != owner)
+        {
+            return Ok(false);
+        }
+        let mut queued_msg = msg.clone();
+        queued_msg.queued = Some(true);
+        let queued = Queued {
+            queued_at: now(),
+            frame: encrypt_message(&self.key, &queued_msg)?,
+            author: msg.author.clone(),
+        };
+
+        let mut store = self.store.lock().unwrap();
+        let queue = store.queues.entry(name.to_string()).or_default();
+        queue.push_back(queued);
+        while queue.len() > self.config.max_per_user {
+            queue.pop_front();
+        }
+        Ok(true)
+    }
+
+    // Removes and returns the messages still held for `name`, oldest first
+    // Only the identity the name belongs to gets them, anyone else using the name gets nothing
+    pub fn take(&self, name: &str, author: &str) -> Vec<Queued> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let owner = store.known.get(name).map(|known| known.author.as_str());
+        if owner != Some(author) {
+            return Vec::new();
+        }
+        store
+            .queues
+            .remove(name)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|queued| !self.expired(queued.queued_at, now))
+            .collect()
+    }
+
+    // Drops expired messages and users, then writes the queue to its file if it has one
+    // The file is written under another name and renamed, so it is never read half written
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = self.config.file.as_deref().filter(|_| self.config.enabled) else {
+            return Ok(());
+        };
+        let _saving = self.saving.lock().await;
+        let encrypted = {
+            let now = now();
+            let mut store = self.store.lock().unwrap();
+            store
+                .known
+                .retain(|_, known| !self.expired(known.connected, now));
+            for queue in store.queues.values_mut() {
+                queue.retain(|queued| !self.expired(queued.queued_at, now));
+            }
+            store.queues.retain(|_, queue| !queue.is_empty());
+            encrypt(&self.key, &*store)?
+        };
+        let partial = format!("{}.partial", path);
+        tokio::fs::write(&partial, encrypted).await?;
+        tokio::fs::rename(&partial, path).await?;
+        Ok(())
+    }
+
+    fn expired(&self, since: u64, now: u64) -> bool {
+        now.saturating_sub(since) >= self.config.ttl_secs
+    }
+}
+
+fn read_store(path: &str, key: &str) -> Result<Store> {
+    let encrypted = match std::fs::read(path) {
+        Ok(encrypted) => encrypted,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Store::default()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read `{}`", path)),
+    };
+    // A nonce alone is too short to hold anything
+    if encrypted.len() <= 12 {
+        return Err(anyhow!("`{}` is damaged", path));
+    }
+    let store: Store = decrypt(key, &encrypted)
+        .with_context(|| format!("`{}` is damaged or was saved with another key", path))?;
+    let held: usize = store.queues.values().map(VecDeque::len).sum();
+    info!("Loaded {} offline message(s) from {}", held, path);
+    Ok(store)
+}
+
+// Seconds since the Unix epoch, stored times must survive a restart
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{decrypt_message, generate_key};
+
+    #[tokio::test]
+    async fn test_offline_queue() {
+        let key = generate_key(32);
+        let path = std::env::temp_dir().join(format!("offline-{}.queue", generate_key(8)));
+        let config = OfflineSection {
+            max_per_user: 2,
+            file: Some(path.to_string_lossy().into_owned()),
+            ..OfflineSection::default()
+        };
+        let msg = |text: &str| Message::new(Some("alice".into()), None, Some(text.into()), None);
+
+        // Only users who connected before get their messages held
+        let queue = OfflineQueue::load(&config, &key);
+        assert!(!queue.push("bob", &msg("hi")).unwrap());
+        assert!(queue.remember("bob", "bob's identity"));
+        for text in ["one", "two", "three"] {
+            assert!(queue.push("bob", &msg(text)).unwrap());
+        }
+        assert_eq!(queue.owner("bob").as_deref(), Some("bob's identity"));
+        // A direct message sent to someone else who had the name isn't held for them
+        let mut direct = msg("psst");
+        direct.recipient = Some("mallory's identity".into());
+        assert!(!queue.push("bob", &direct).unwrap());
+        // Saves at the same time don't trip over each other's partial file
+        let saves = tokio::join!(queue.save(), queue.save(), queue.save());
+        assert!(saves.0.is_ok() && saves.1.is_ok() && saves.2.is_ok());
+
+        // The file can only be read with the key, and the oldest message was dropped
+        let saved = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&saved).contains("bob"));
+        let queue = OfflineQueue::load(&config, &key);
+        assert!(!queue.remember("bob", "mallory's identity"));
+        assert!(queue.take("bob", "mallory's identity").is_empty());
+        let held: Vec<Message> = queue
+            .take("bob", "bob's identity")
+            .iter()
+            .map(|queued| decrypt_message(&key, &queued.frame).unwrap())
+            .collect();
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0].message.as_deref(), Some("two"));
+        assert_eq!(held[0].queued, Some(true));
+        assert!(queue.take("bob", "bob's identity").is_empty());
+
+        let other_key = generate_key(32);
+        assert_eq!(OfflineQueue::load(&config, &other_key).owner("bob"), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
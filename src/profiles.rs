@@ -0,0 +1,379 @@
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use tokio::time::Duration;
+
+use crate::tools::{
+    decrypt, encrypt, generate_key, get_user_input, HeartbeatConfig, MAX_HEARTBEAT_INTERVAL_SECS,
+    MAX_HEARTBEAT_MISSED,
+};
+
+const PROFILES_DIR: &str = ".crypted-messages";
+const PROFILES_FILE: &str = "profiles.toml";
+const IDENTITY_FILE: &str = "identity";
+const SALT_SIZE: usize = 16;
+
+// Saved client profiles, read from `~/.crypted-messages/profiles.toml` unless another path is given
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct ProfilesFile {
+    pub profiles: BTreeMap<String, Profile>,
+    pub keys: BTreeMap<String, SealedKey>,
+}
+
+// A named server, anything missing is asked interactively
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct Profile {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<String>,    // Name of an entry in [keys]
+    pub name: Option<String>,   // Preferred nickname
+    pub color: Option<bool>,    // Color mode, on when missing
+    pub notify: Option<String>, // Command run when someone mentions you, the bell rings otherwise
+    // Seconds between pings to the server, and unanswered pings before reconnecting
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_missed: Option<u32>,
+}
+
+// AES key encrypted under a passphrase, both fields are hexadecimal
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SealedKey {
+    pub salt: String,
+    pub data: String,
+}
+
+// What the client needs to start, filled from a profile or left empty to prompt for everything
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<String>,
+    pub name: Option<String>,
+    pub color: bool,
+    pub fingerprint: Option<String>, // Key fingerprint the server announced, checked before connecting
+    pub invite: Option<String>,      // ID of the invite used to join, sent on the first connection
+    pub notify: Option<String>,      // Command run when someone mentions you
+    pub heartbeat: HeartbeatConfig,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            address: None,
+            port: None,
+            key: None,
+            name: None,
+            color: true,
+            fingerprint: None,
+            invite: None,
+            notify: None,
+            heartbeat: HeartbeatConfig::default(),
+        }
+    }
+}
+
+pub fn default_path() -> PathBuf {
+    home_dir().join(PROFILES_DIR).join(PROFILES_FILE)
+}
+
+fn home_dir() -> PathBuf {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+// The secret the server knows this client by, kept in `~/.crypted-messages/identity`
+// It is made on first use, the server holds offline messages for it and lets it edit its messages
+pub fn load_identity() -> Result<String> {
+    read_or_create_identity(&home_dir().join(PROFILES_DIR).join(IDENTITY_FILE))
+}
+
+fn read_or_create_identity(path: &Path) -> Result<String> {
+    if path.exists() {
+        let identity = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read identity `{}`", path.display()))?;
+        return Ok(identity.trim().to_string());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create `{}`", dir.display()))?;
+    }
+    let identity = generate_key(32);
+    fs::write(path, &identity)
+        .with_context(|| format!("Failed to write identity `{}`", path.display()))?;
+    // Anyone who reads it can pass for us, only the owner may
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(identity)
+}
+
+impl ProfilesFile {
+    // A missing file is the same as an empty one
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(ProfilesFile::default());
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read profiles `{}`", path.display()))?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow!("Failed to parse profiles `{}`: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+        }
+        let contents = toml::to_string_pretty(self)?;
+        fs::write(path, contents)
+            .with_context(|| format!("Failed to write profiles `{}`", path.display()))
+    }
+
+    // Resolves a profile, asking for the passphrase when it references a key
+    pub fn settings(&self, profile_name: &str) -> Result<ClientSettings> {
+        let profile = self.profiles.get(profile_name).ok_or_else(|| {
+            let available: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            anyhow!(
+                "No profile `{}`, available profiles: {}",
+                profile_name,
+                match available.is_empty() {
+                    true => "none".to_string(),
+                    false => available.join(", "),
+                }
+            )
+        })?;
+
+        let key = match &profile.key {
+            Some(key_name) => {
+                let sealed = self.keys.get(key_name).ok_or_else(|| {
+                    anyhow!(
+                        "Invalid profile value `profiles.{}.key`: no key named `{}`",
+                        profile_name,
+                        key_name
+                    )
+                })?;
+                let prompt = format!("Enter the passphrase for key `{}`: ", key_name);
+                let passphrase = read_passphrase(&prompt)?;
+                Some(open_key(&passphrase, sealed).with_context(|| {
+                    format!("Failed to unlock key `{}`, wrong passphrase?", key_name)
+                })?)
+            }
+            None => None,
+        };
+
+        let mut heartbeat = HeartbeatConfig::default();
+        if let Some(interval) = profile.heartbeat_interval {
+            if !(1..=MAX_HEARTBEAT_INTERVAL_SECS).contains(&interval) {
+                let max = MAX_HEARTBEAT_INTERVAL_SECS;
+                return Err(invalid_value(profile_name, "heartbeat_interval", max));
+            }
+            heartbeat.interval = Duration::from_secs(interval);
+        }
+        if let Some(max_missed) = profile.heartbeat_missed {
+            if !(1..=MAX_HEARTBEAT_MISSED).contains(&max_missed) {
+                let max = MAX_HEARTBEAT_MISSED.into();
+                return Err(invalid_value(profile_name, "heartbeat_missed", max));
+            }
+            heartbeat.max_missed = max_missed;
+        }
+
+        Ok(ClientSettings {
+            address: profile.address.clone(),
+            port: profile.port,
+            key,
+            name: profile.name.clone(),
+            color: profile.color.unwrap_or(true),
+            fingerprint: None,
+            invite: None,
+            notify: profile.notify.clone(),
+            heartbeat,
+        })
+    }
+}
+
+fn invalid_value(profile_name: &str, field: &str, max: u64) -> anyhow::Error {
+    anyhow!(
+        "Invalid profile value `profiles.{}.{}`: must be between 1 and {}",
+        profile_name,
+        field,
+        max
+    )
+}
+
+// Asks for every profile field and stores it, the key is sealed under a new passphrase
+pub fn save_profile_interactive(path: &Path, profile_name: &str) -> Result<()> {
+    let mut file = ProfilesFile::load(path)?;
+
+    let optional = |input: String| (!input.is_empty()).then_some(input);
+    let address = optional(get_user_input(Some(
+        "Server IP address (blank to ask each time): ",
+    )));
+    let port = match optional(get_user_input(Some(
+        "Server port (blank to ask each time): ",
+    ))) {
+        Some(port) => Some(port.parse::<u16>().context("Invalid port number")?),
+        None => None,
+    };
+    let name = optional(get_user_input(Some(
+        "Preferred name (blank to ask each time): ",
+    )));
+    let color = !get_user_input(Some("Use colors? [Y/n]: "))
+        .to_lowercase()
+        .starts_with('n');
+    let notify = optional(get_user_input(Some(
+        "Command to run when mentioned (blank to ring the bell): ",
+    )));
+
+    let key = match optional(read_passphrase("Server key (blank to ask each time): ")?) {
+        Some(key) => {
+            if key.len() != 64 || hex::decode(&key).is_err() {
+                return Err(anyhow!("Invalid key, expected 64 hexadecimal characters"));
+            }
+            let passphrase = read_passphrase("Passphrase to protect the key: ")?;
+            if passphrase.is_empty() {
+                return Err(anyhow!("The passphrase can't be empty"));
+            }
+            if read_passphrase("Repeat the passphrase: ")? != passphrase {
+                return Err(anyhow!("The passphrases don't match"));
+            }
+            file.keys
+                .insert(profile_name.to_string(), seal_key(&passphrase, &key)?);
+            Some(profile_name.to_string())
+        }
+        None => None,
+    };
+
+    file.profiles.insert(
+        profile_name.to_string(),
+        Profile {
+            address,
+            port,
+            key,
+            name,
+            color: Some(color),
+            notify,
+            ..Profile::default()
+        },
+    );
+    file.save(path)?;
+    println!("Profile `{}` saved to {}", profile_name, path.display());
+    Ok(())
+}
+
+// Derives a 32 byte AES key from the passphrase, as hexadecimal for `tools::encrypt`
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<String> {
+    let mut derived = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut derived)
+        .map_err(|e| anyhow!("Failed to derive key from passphrase: {}", e))?;
+    Ok(hex::encode(derived))
+}
+
+pub fn seal_key(passphrase: &str, key: &str) -> Result<SealedKey> {
+    let mut salt = [0u8; SALT_SIZE];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let data = encrypt(&derive_key(passphrase, &salt)?, &key.to_string())?;
+    Ok(SealedKey {
+        salt: hex::encode(salt),
+        data: hex::encode(data),
+    })
+}
+
+pub fn open_key(passphrase: &str, sealed: &SealedKey) -> Result<String> {
+    let salt = hex::decode(&sealed.salt)?;
+    let data = hex::decode(&sealed.data)?;
+    decrypt(&derive_key(passphrase, &salt)?, &data)
+}
+
+// Reads a line without echoing it on interactive terminals
+fn read_passphrase(prompt: &str) -> Result<String> {
+    if !io::stdin().is_terminal() {
+        return Ok(get_user_input(Some(prompt)));
+    }
+
+    print!("> {}", prompt);
+    io::stdout().flush()?;
+    terminal::enable_raw_mode()?;
+    let result = read_hidden_line();
+    terminal::disable_raw_mode()?;
+    println!();
+    result
+}
+
+fn read_hidden_line() -> Result<String> {
+    let mut line = String::new();
+    loop {
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => return Ok(line),
+            KeyCode::Char('c') if ctrl => return Err(anyhow!("Cancelled")),
+            KeyCode::Backspace => {
+                line.pop();
+            }
+            KeyCode::Char(c) if !ctrl => line.push(c),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_key() {
+        let key = "ab".repeat(32);
+        let sealed = seal_key("correct horse", &key).unwrap();
+        assert!(!sealed.data.contains(&key));
+        assert_eq!(open_key("correct horse", &sealed).unwrap(), key);
+        assert!(open_key("wrong horse", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_profile_heartbeat() {
+        let mut file: ProfilesFile =
+            toml::from_str("[profiles.work]\nheartbeat_interval = 5\nheartbeat_missed = 2\n")
+                .unwrap();
+        let heartbeat = file.settings("work").unwrap().heartbeat;
+        assert_eq!(heartbeat.interval, Duration::from_secs(5));
+        assert_eq!(heartbeat.timeout(), Duration::from_secs(10));
+
+        for interval in [0, u64::MAX] {
+            file.profiles.get_mut("work").unwrap().heartbeat_interval = Some(interval);
+            let error = file.settings("work").unwrap_err().to_string();
+            assert!(error.contains("profiles.work.heartbeat_interval"));
+        }
+    }
+
+    #[test]
+    fn test_identity() {
+        let dir = env::temp_dir().join(format!("identity-{}", generate_key(8)));
+        let path = dir.join(IDENTITY_FILE);
+        let identity = read_or_create_identity(&path).unwrap();
+        assert_eq!(identity.len(), 64);
+        assert_eq!(read_or_create_identity(&path).unwrap(), identity);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,25 +1,183 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, DashSet};
 use local_ip_address::local_ip;
 use rand::Rng;
-use std::collections::HashSet;
-use std::collections::{HashMap, VecDeque};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::BufReader;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::task;
-use tokio::time::{timeout, Duration};
-
+use tokio::time::{interval, timeout, Duration, Instant};
+
+use crate::config::{BindPolicy, BindSection, ServerConfig};
+use crate::discovery::{self, Announcement};
+use crate::invite::{Invite, INVITE_ID_SIZE};
+use crate::logger::{debug, error, info, sensitive, warning};
+use crate::metrics::{self, Rejection, METRICS};
+use crate::offline::OfflineQueue;
 use crate::tools::{
-    decrypt_handshake, decrypt_message, encrypt_handshake, encrypt_message, generate_key,
-    get_timestamp, Client, Handshake, Message, SerdeColor, ServerCommand,
+    check_cooldown, collect_thread, command_argument, decrypt_handshake, decrypt_message,
+    encrypt_handshake, encrypt_message, format_duration, generate_key, get_timestamp,
+    key_fingerprint, mentions, read_frame, snippet, write_frame, Client, Delivery, Frame,
+    Handshake, HeartbeatConfig, Message, Outbound, Outgoing, SearchQuery, SerdeColor,
+    ServerCommand, SlowClientPolicy, TokenBucket, DELETE_SIGNAL, DELIVERED_SIGNAL,
+    FRAME_HEADER_SIZE, NAME_CHANGE_SIGNAL, PING_SIGNAL, PONG_SIGNAL, REACTION_SIGNAL, READ_SIGNAL,
+    SENT_SIGNAL, TYPING_SIGNAL,
 };
-use crate::tools::{get_ip, get_port, random_color, AdressMode};
+use crate::tools::{format_address, get_ip, get_port, parse_host, random_color, AdressMode};
 
-type SharedState = Arc<Mutex<HashMap<usize, Client>>>;
+type SharedState = Arc<State>;
 pub type AssignedColors = Arc<Mutex<HashSet<SerdeColor>>>;
 type Key = Arc<String>;
 type SudoKey = Arc<String>;
 type History = Arc<Mutex<VecDeque<Message>>>;
+type LastHeard = Arc<Mutex<Instant>>; // When a frame was last received from the client
+type Config = Arc<ServerConfig>;
+
+// Connected clients, sharded so looking one up or broadcasting never locks the whole server
+// Map guards are never held across an await, nor while touching the same map again
+struct State {
+    clients: DashMap<usize, Client>,
+    names: DashMap<String, usize>, // Who owns each name, so two clients can't claim the same one
+    mutes: DashMap<String, Instant>, // Muted for flooding until then, by `FloodGuard::who`
+    invites: DashMap<String, Invitation>, // Outstanding invites by ID, lost when the server stops
+    public_address: (String, u16), // Where invites send people
+    offline: OfflineQueue,         // Direct messages and mentions held for users who are away
+    // Identities that joined with an invite, let back in when invites are required
+    admitted: DashSet<String>,
+    // Stored messages waiting to be queued for their recipients, in ID order
+    dispatch: mpsc::Sender<Dispatch>,
+    joined: AtomicUsize, // Clients past the handshake, counted before they are registered
+}
+
+// A stored message and the queues it goes to, see `dispatch_messages`
+struct Dispatch {
+    frame: Frame,
+    outbounds: Vec<Outbound>,
+    delivery: Option<Delivery>,
+}
+
+// An invite handed out with /invite, checked when someone joins with it
+struct Invitation {
+    expires: Option<Instant>,
+    single_use: bool,
+}
+
+// Open connections, in total and per address, including those still in the handshake
+#[derive(Default)]
+struct Connections {
+    total: AtomicUsize,
+    per_ip: DashMap<IpAddr, usize>,
+}
+
+// A connection counted in `Connections`, released when the handler task drops it
+struct ConnectionSlot {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections.total.fetch_sub(1, Ordering::Relaxed);
+        if let Entry::Occupied(mut entry) = self.connections.per_ip.entry(self.ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+// A place among `limits.max_clients`, released when the client's handler is done
+struct ClientSlot {
+    state: SharedState,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.state.joined.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Per connection flood protection, message and byte budgets refilled over time
+struct FloodGuard {
+    who: String, // Mutes are kept under this so reconnecting doesn't lift them
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    reads: TokenBucket,
+    throttled: u32,               // Messages refused in a row
+    last_notice: Option<Instant>, // Throttling notices are sent at most once per second
+}
+
+impl FloodGuard {
+    fn new(who: String, config: &ServerConfig) -> Self {
+        let limits = &config.rate_limits;
+        FloodGuard {
+            who,
+            messages: TokenBucket::new(limits.messages_per_sec, limits.message_burst as f64),
+            bytes: TokenBucket::new(limits.bytes_per_sec, limits.byte_burst as f64),
+            reads: TokenBucket::new(READS_PER_SEC, READ_BURST),
+            throttled: 0,
+            last_notice: None,
+        }
+    }
+}
+
+// Who is changing a stored message
+enum Editor {
+    Sudo,           // Can change anyone's
+    Author(String), // Fingerprint of their identity, changes their own only
+}
+
+impl Editor {
+    fn wrote(&self, msg: &Message) -> bool {
+        match self {
+            Editor::Author(author) => msg.author.as_deref() == Some(author.as_str()),
+            Editor::Sudo => false,
+        }
+    }
+}
+
+// Presence changes a user can make about themselves
+enum Presence {
+    Away(String),
+    Back,
+    Status(String),
+}
+
+// Characters of the original message quoted in a reply
+const QUOTE_SIZE: usize = 60;
+
+// Longest reaction, in characters, and different reactions a message can have
+const MAX_REACTION_SIZE: usize = 16;
+const MAX_REACTIONS: usize = 20;
+// How many of the latest mentions /mentions lists
+const MENTIONS_SHOWN: usize = 20;
+// Results shown per page of /search
+const SEARCH_PAGE_SIZE: usize = 10;
+
+// Open invites at once, each one is kept until it is used up or expires
+const MAX_INVITES: usize = 256;
+const INVITE_USAGE: &str = "Usage: /invite [minutes until it expires] [once]";
+
+// Connections accepted on any listener, waiting for the limits to be checked
+const ACCEPT_QUEUE: usize = 64;
+// Stored messages waiting to be queued, storing more waits for room without the history lock
+const DISPATCH_QUEUE: usize = 1024;
+// Read receipts each client may send per second, and at once after catching up on history
+// They don't spend the message budget, the ones over this are dropped
+const READS_PER_SEC: f64 = 20.0;
+const READ_BURST: f64 = 200.0;
+
+// Client IDs are never reused, so a late cleanup can't remove a newer client
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(0);
 
 const SUDO_MESSAGE: &str = "
 You have been granted sudo privileges.
@@ -27,107 +185,345 @@ Use /change-color to change your color.
 Use /view-messages to view your messages.
 Use /view-history to view global chat history.
 Use /view-key to view the AES key.
+Use /edit <id> <text> and /delete <id> on anyone's messages.
+Use /invite [minutes] [once] to create an invite link, it contains the key.
+Expired and used invites only stop joining when admin.require_invite is on.
 ";
 
 pub async fn main_server(
-    key: Option<String>,
+    config: ServerConfig,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Ask for the IP address and port to bind the server to
-    let ip = get_ip(
-        None,
-        Some("Enter the IP address (leave blank if unsure): "),
-        AdressMode::Server,
-    )?;
+    // Ask for the IP address and port to bind the server to, unless the config sets them
+    let addresses = match config.server.addresses.is_empty() {
+        true => vec![get_ip(
+            None,
+            Some("Enter the IP address (leave blank if unsure): "),
+            AdressMode::Server,
+        )?],
+        false => config
+            .server
+            .addresses
+            .iter()
+            .map(|address| get_ip(Some(address), None, AdressMode::Server))
+            .collect::<Result<_, _>>()?,
+    };
     let port = get_port(
-        None,
+        config.server.port.map(|port| port.to_string()),
         Some("Enter the port to bind the server to (leave blank for OS assign): "),
         AdressMode::Server,
     )?;
 
-    let listener = setup_tcp_listener(ip, port).await?;
+    let listeners = setup_tcp_listeners(&addresses, port, &config.server).await?;
 
     // Generate the SharedState and Key
-    let key: Key = Arc::new(set_aes_key(key));
-    let sudo_key: SudoKey = Arc::new(set_sudo_key());
-    let state: SharedState = Arc::new(Mutex::new(HashMap::new()));
+    let key: Key = Arc::new(set_aes_key(config.resolve_key()?));
+    let sudo_key: SudoKey = Arc::new(set_sudo_key(&config));
+    let public_address = public_address(&config, &listeners[0])?;
+    let fingerprint = key_fingerprint(&key);
+    info!("Key fingerprint: {}", fingerprint);
+    if config.discovery.enabled {
+        let port = listeners[0].local_addr()?.port();
+        let announcement = Announcement::new(config.discovery.name.clone(), port, fingerprint);
+        task::spawn(discovery::announce(config.discovery.clone(), announcement));
+    }
+    if config.metrics.enabled {
+        let address = (config.metrics.address.as_str(), config.metrics.port);
+        let metrics_listener = TcpListener::bind(address).await?;
+        task::spawn(metrics::serve(metrics_listener));
+    }
+    let config: Config = Arc::new(config);
+    let (dispatch, dispatch_rx) = mpsc::channel(DISPATCH_QUEUE);
+    task::spawn(dispatch_messages(dispatch_rx));
+    let state: SharedState = Arc::new(State {
+        clients: DashMap::new(),
+        names: DashMap::new(),
+        mutes: DashMap::new(),
+        invites: DashMap::new(),
+        public_address,
+        offline: OfflineQueue::load(&config.offline, &key),
+        admitted: DashSet::new(),
+        dispatch,
+        joined: AtomicUsize::new(0),
+    });
+    // Nobody could get in to create the first invite otherwise
+    if config.admin.require_invite {
+        let invite = create_invite("once", &state, &key)?;
+        println!("[SERVER] Only users with an invite can join. {}", invite);
+    }
     let assigned_colors: AssignedColors = Arc::new(Mutex::new(HashSet::new()));
     let history: History = Arc::new(Mutex::new(VecDeque::new()));
+    let connections = Arc::new(Connections::default());
+    let mut accept_bucket = TokenBucket::new(
+        config.rate_limits.accept_per_sec,
+        config.rate_limits.accept_burst as f64,
+    );
+
+    // Every listener feeds the same queue, so the limits apply across all of them
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(ACCEPT_QUEUE);
+    for listener in listeners {
+        let accepted_tx = accepted_tx.clone();
+        task::spawn(
+            async move { while accepted_tx.send(listener.accept().await).await.is_ok() {} },
+        );
+    }
 
     // Main loop to accept incoming connections
-    loop {
-        match listener.accept().await {
-            Ok((socket, _)) => {
+    while let Some(accepted) = accepted_rx.recv().await {
+        match accepted {
+            Ok((socket, address)) => {
+                // Connections over a limit are dropped before any work is done for them
+                let slot = match accept_bucket.try_take() {
+                    true => reserve_connection(&connections, address.ip(), &config),
+                    false => Err(Rejection::AcceptRate),
+                };
+                let slot = match slot {
+                    Ok(slot) => slot,
+                    Err(rejection) => {
+                        METRICS.connection_rejected(rejection);
+                        warning!(
+                            "Rejected connection from {}: {} limit reached",
+                            address.ip(),
+                            rejection.as_str()
+                        );
+                        continue;
+                    }
+                };
+
+                // Chat frames are small and should go out right away
+                if let Err(e) = socket.set_nodelay(true) {
+                    warning!("Failed to disable Nagle's algorithm: {}", e);
+                }
                 spawn_client_handler(
                     socket,
+                    slot,
                     state.clone(),
                     key.clone(),
                     sudo_key.clone(),
                     assigned_colors.clone(),
                     history.clone(),
+                    config.clone(),
                 );
             }
             Err(e) => {
-                eprintln!("Failed to accept connection: {:?}", e);
+                error!("Failed to accept connection: {:?}", e);
             }
         }
     }
+    Ok(())
+}
+
+// Counts a new connection from `ip`, unless it would go over the total or per address limit
+fn reserve_connection(
+    connections: &Arc<Connections>,
+    ip: IpAddr,
+    config: &Config,
+) -> Result<ConnectionSlot, Rejection> {
+    if connections.total.fetch_add(1, Ordering::Relaxed) >= config.limits.max_connections {
+        connections.total.fetch_sub(1, Ordering::Relaxed);
+        return Err(Rejection::MaxConnections);
+    }
+
+    let mut per_ip = connections.per_ip.entry(ip).or_insert(0);
+    if *per_ip >= config.limits.max_connections_per_ip {
+        drop(per_ip);
+        connections.total.fetch_sub(1, Ordering::Relaxed);
+        return Err(Rejection::PerIp);
+    }
+    *per_ip += 1;
+    drop(per_ip);
+
+    Ok(ConnectionSlot {
+        connections: connections.clone(),
+        ip,
+    })
+}
+
+// Taken before the client is registered, so clients joining at once can't go over the limit
+fn reserve_client(state: &SharedState, max_clients: usize) -> Option<ClientSlot> {
+    if state.joined.fetch_add(1, Ordering::Relaxed) >= max_clients {
+        state.joined.fetch_sub(1, Ordering::Relaxed);
+        return None;
+    }
+    Some(ClientSlot {
+        state: state.clone(),
+    })
 }
 
 // Helper function to generate an AES key
 fn set_aes_key(key: Option<String>) -> String {
     key.unwrap_or_else(|| {
         let server_key = generate_key(32);
+        // Printed for the operator only, secrets never go through the logger
         println!("[SERVER] Generated server key: {}", server_key);
         server_key
     })
 }
 
-fn set_sudo_key() -> String {
+fn set_sudo_key(config: &ServerConfig) -> String {
+    if !config.admin.sudo_enabled {
+        info!("Sudo is disabled");
+        return String::new();
+    }
+    if let Some(code) = &config.admin.sudo_code {
+        info!("Sudo code loaded from config");
+        return code.clone();
+    }
     let code = rand::thread_rng().gen_range(1000..9999).to_string();
     println!("[SERVER] Sudo code generated: {}", code);
     code
 }
 
-// Setup the TCP listener
-async fn setup_tcp_listener(
-    ip: String,
-    mut port: u16,
-) -> Result<TcpListener, Box<dyn std::error::Error + Send + Sync>> {
-    loop {
-        println!("[SERVER] Binding to {}:{}", ip, port);
-        match TcpListener::bind(format!("{}:{}", ip, port)).await {
-            Ok(listener) => {
-                println!(
-                    "[SERVER] Running on {}:{}, public IP: {}. Waiting for connections...",
-                    listener.local_addr().unwrap().ip(),
-                    listener.local_addr().unwrap().port(),
-                    local_ip().unwrap_or_else(|_| "Unknown ip".parse().unwrap()),
-                );
-                return Ok(listener);
+// The address put in invites: the configured one, else the bound IP, else the local IP
+fn public_address(
+    config: &ServerConfig,
+    listener: &TcpListener,
+) -> Result<(String, u16), Box<dyn std::error::Error + Send + Sync>> {
+    let bound = listener.local_addr()?;
+    let host = match config.server.public_address.as_deref().and_then(parse_host) {
+        Some(host) => host,
+        None if bound.ip().is_unspecified() => local_ip().unwrap_or(bound.ip()).to_string(),
+        None => bound.ip().to_string(),
+    };
+    Ok((host, bound.port()))
+}
+
+// Setup the TCP listeners, every address is bound on the same port
+// A taken port is handled as `server.bind_policy` says, the bound one goes to `server.port_file`
+async fn setup_tcp_listeners(
+    addresses: &[String],
+    port: u16,
+    bind: &BindSection,
+) -> Result<Vec<TcpListener>, Box<dyn std::error::Error + Send + Sync>> {
+    let listeners = match bind.bind_policy {
+        BindPolicy::Fail => bind_all(addresses, port).await?,
+        BindPolicy::Any => bind_all(addresses, 0).await?,
+        BindPolicy::Range => {
+            let last = bind.port_range_end.unwrap_or(port);
+            if port > last {
+                return Err(format!("Invalid port range {}-{}", port, last).into());
             }
-            Err(e) => {
-                eprintln!("Failed to bind to port {}: {}. ", port, e);
-                eprintln!("Trying port {}.", port + 10);
-                port += 10;
+            let mut candidate = port;
+            loop {
+                match bind_all(addresses, candidate).await {
+                    Ok(listeners) => break listeners,
+                    Err(e) if candidate >= last => {
+                        return Err(format!("No free port in {}-{}: {}", port, last, e).into())
+                    }
+                    Err(e) => {
+                        warning!("{}, trying port {}", e, candidate + 1);
+                        candidate += 1;
+                    }
+                }
             }
         }
+    };
+
+    let bound: Vec<String> = listeners
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .map(|address| address.to_string())
+        .collect();
+    info!(
+        "Running on {}, public IP: {}. Waiting for connections...",
+        bound.join(", "),
+        local_ip().unwrap_or_else(|_| "Unknown ip".parse().unwrap()),
+    );
+    if let Some(path) = &bind.port_file {
+        let port = listeners[0].local_addr()?.port();
+        write_port_file(path, port)
+            .map_err(|e| format!("Failed to write server.port_file `{}`: {}", path, e))?;
+    }
+    Ok(listeners)
+}
+
+// Binds every address on the same port, the first address picks it when the port is 0
+async fn bind_all(addresses: &[String], mut port: u16) -> Result<Vec<TcpListener>, String> {
+    let mut listeners = Vec::new();
+    for address in addresses {
+        info!("Binding to {}", format_address(address, port));
+        let listener = bind_listener(address, port)
+            .await
+            .map_err(|e| format!("Failed to bind to {}: {}", format_address(address, port), e))?;
+        port = listener.local_addr().map_err(|e| e.to_string())?.port();
+        listeners.push(listener);
+    }
+    match listeners.is_empty() {
+        true => Err("No address to bind to".to_string()),
+        false => Ok(listeners),
+    }
+}
+
+// Tells scripts which port the server ended up on
+// The file is written under another name and renamed, so it is never read half written
+fn write_port_file(path: &str, port: u16) -> io::Result<()> {
+    if path == "-" {
+        println!("PORT={}", port);
+        return std::io::Write::flush(&mut std::io::stdout());
+    }
+    let partial = format!("{}.partial", path);
+    std::fs::write(&partial, format!("{}\n", port))?;
+    std::fs::rename(&partial, path)
+}
+
+// Binds the first address the host resolves to that works
+// The IPv6 wildcard (::) also accepts IPv4 connections, whatever the system default is
+async fn bind_listener(host: &str, port: u16) -> io::Result<TcpListener> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+    for address in tokio::net::lookup_host((host, port)).await? {
+        match bind_socket(address) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn bind_socket(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
     }
+    // Like `TcpListener::bind`, so a restarted server gets its port back right away
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
 }
 
 // Spawns a task to handle the client connection
+#[allow(clippy::too_many_arguments)]
 fn spawn_client_handler(
     socket: TcpStream,
+    slot: ConnectionSlot,
     state: SharedState,
     key: Key,
     sudo_key: SudoKey,
     assigned_colors: AssignedColors,
     history: History,
+    config: Config,
 ) {
     task::spawn(async move {
-        if let Err(e) = handle_client(socket, state, key, sudo_key, assigned_colors, history).await
+        if let Err(e) = handle_client(
+            socket,
+            state,
+            key,
+            sudo_key,
+            assigned_colors,
+            history,
+            config,
+        )
+        .await
         {
-            eprintln!("Failed to handle client: {:?}", e);
+            warning!("Failed to handle client: {:?}", e);
         }
+        // The connection stops counting against the limits once the handler is done
+        drop(slot);
     });
 }
 
@@ -139,145 +535,409 @@ async fn handle_client(
     sudo_key: SudoKey,
     assigned_colors: AssignedColors,
     history: History,
+    config: Config,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let ip = socket.peer_addr()?.ip();
     let (reader, writer) = tokio::io::split(socket);
     let mut reader = BufReader::new(reader);
     let writer = Arc::new(Mutex::new(writer));
 
     // Generate a unique client ID
-    let id = get_client_id(&state).await;
+    let id = get_client_id();
 
     // Perform the handshake with the client to get the initial name
-    let initial_name = perform_handshake(&key, &mut reader).await?;
-
-    // Collect all names under lock, but release lock afterward for name generation
-    let existing_names: Vec<String> = {
-        let state_guard = state.lock().await;
-        state_guard
-            .values()
-            .map(|client| client.name.clone())
-            .collect()
+    let handshake = perform_handshake(&key, &mut reader, config.handshake_timeout()).await?;
+    let initial_name = handshake.name.clone();
+
+    // Turn the client away when the server is full, it can try again later
+    // The slot is held until the client is cleaned up
+    let Some(_slot) = reserve_client(&state, config.limits.max_clients) else {
+        warning!("{} refused, the server is full", initial_name);
+        let reason = format!(
+            "The server is full ({} users), try again later",
+            config.limits.max_clients
+        );
+        return refuse_client(&writer, &key, &reason, true).await;
     };
 
-    // Generate a unique name by appending a counter if necessary
-    let mut name = initial_name.clone();
-    let mut counter = 1;
-    while existing_names.contains(&name) {
-        name = format!("{}-{}", initial_name, counter);
-        counter += 1;
+    // Clients that don't send an identity can only change messages from this connection
+    let identity = handshake
+        .identity
+        .clone()
+        .unwrap_or_else(|| generate_key(32));
+    let author = key_fingerprint(&identity);
+
+    // Mutes follow the identity, so one flooder doesn't mute everyone behind the same address
+    // Clients without one can only be told apart by address
+    let who = match handshake.identity {
+        Some(_) => author.clone(),
+        None => ip.to_string(),
+    };
+    let mut flood = FloodGuard::new(who, &config);
+
+    // An invite that can't be used turns the client away, only a full server leaves it unspent
+    // When invites are required, only those who joined with one are let back in without it
+    let refused = match &handshake.invite {
+        Some(invite) => redeem_invite(&state.invites, invite).err(),
+        None if config.admin.require_invite && !state.admitted.contains(&author) => {
+            Some("This server only lets in users with an invite")
+        }
+        None => None,
+    };
+    if let Some(reason) = refused {
+        warning!("{} refused: {}", initial_name, reason);
+        return refuse_client(&writer, &key, reason, false).await;
+    }
+    if handshake.invite.is_some() {
+        info!("{} joined with an invite", initial_name);
+        if config.admin.require_invite {
+            state.admitted.insert(author.clone());
+        }
     }
 
-    // Now the name is guaranteed to be unique, continue with client registration
-
-    let color = assign_random_color(&state, &assigned_colors, &id).await?;
+    // Claim a unique name by appending a counter if necessary
+    let name = claim_name(&state.names, &initial_name, id);
 
-    // Register the client with an empty message history
-    let (tx, rx) = broadcast::channel(10);
-    let client = Client::new(name.clone(), tx, color);
+    // Now the name is guaranteed to be unique, continue with client registration
 
-    state.lock().await.insert(id, client.clone());
-    println!("{} connected (ID: {})", name, id);
+    // Resuming clients ask for their previous color back
+    let color = assign_color(&assigned_colors, handshake.color).await;
+
+    // Register the client with an empty message history and a bounded outbound queue
+    let (outbound, rx) = Outbound::new(config.limits.outbound_queue, config.limits.slow_clients);
+    let mut client = Client::new(name.clone(), outbound, color);
+    client.author = Some(author.clone());
+
+    // The state owns the only sender, so removing the client stops its message task
+    // It is registered under the history lock: every message stored until then is replayed,
+    // every later one is queued for it
+    let latest = {
+        let history_guard = history.lock().await;
+        state.clients.insert(id, client);
+        latest_message_id(&history_guard)
+    };
+    METRICS.client_connected();
+    info!("{} connected (ID: {})", name, id);
+
+    // The client is registered from here on, a failed write still has to clean it up
+    let greeted = async {
+        // Send handshake response and welcome message
+        send_handshake_response(&key, &name, &writer, color, latest).await?;
+        send_welcome_message(&key, &name, &writer, color, &state).await?;
+
+        // Replay what a resuming client missed before live messages start flowing
+        if let Some(last_seen) = handshake.last_seen {
+            send_missed_messages(
+                &key, &name, &author, &writer, &history, last_seen, latest, color,
+            )
+            .await?;
+        }
+        // Then what was held for them while they were offline, from now on they are a known user
+        // Only for the identity the name belongs to, someone else taking the name gets nothing
+        if state.offline.remember(&name, &author) {
+            let last_seen = handshake.last_seen;
+            send_queued_messages(&key, &name, &author, &writer, &state, &history, last_seen)
+                .await?;
+        }
 
-    // Send handshake response and welcome message
-    send_handshake_response(&key, &name, &writer, color).await?;
-    send_welcome_message(&key, &name, &writer, color).await?;
+        announce_join(&key, &state, &id, &name, color).await
+    }
+    .await;
+    if let Err(e) = greeted {
+        cleanup_client(&key, state, &assigned_colors, &name, &id).await;
+        return Err(e);
+    }
 
     // Spawn task to handle outgoing messages
     let writer_clone = Arc::clone(&writer);
-    let tx_task = spawn_message_sender(writer_clone.clone(), rx, &name);
-
-    // Main loop to handle incoming messages
-    let result = handle_incoming_messages(
-        &key,
-        &state,
+    let mut tx_task = spawn_message_sender(
+        writer_clone.clone(),
+        rx,
         &name,
-        &id,
-        &mut reader,
-        &writer_clone,
-        sudo_key,
-        color,
-        history,
-    )
-    .await;
+        key.clone(),
+        state.clone(),
+        config.limits.slow_clients,
+    );
+    let mut tx_finished = false;
+
+    // Main loop to handle incoming messages, until the client leaves or stops answering pings
+    let last_heard: LastHeard = Arc::new(Mutex::new(Instant::now()));
+    let result = tokio::select! {
+        result = handle_incoming_messages(
+            &key,
+            &state,
+            &name,
+            &id,
+            &mut reader,
+            &writer_clone,
+            sudo_key,
+            color,
+            history,
+            &last_heard,
+            &mut flood,
+            &config,
+        ) => result,
+        result = run_heartbeat(&key, &writer_clone, &last_heard, config.heartbeat(), color) => {
+            warning!("{} stopped answering heartbeats, evicting", name);
+            // The sender may be stuck writing to the dead connection
+            tx_task.abort();
+            result
+        }
+        // The sender stops on its own when the connection breaks or the client can't keep up
+        _ = &mut tx_task => {
+            tx_finished = true;
+            Ok(())
+        }
+    };
 
     // Clean up the client on disconnect
-    cleanup_client(state, &name, &id).await;
+    cleanup_client(&key, state, &assigned_colors, &name, &id).await;
 
     // Wait for the message task to finish
-    tx_task.await?;
+    if !tx_finished {
+        match tx_task.await {
+            Err(e) if !e.is_cancelled() => return Err(e.into()),
+            _ => (),
+        }
+    }
 
     result
 }
 
+// Turns a client away, the reason replaces the handshake response
+// `retry` tells the client whether trying again later can work
+async fn refuse_client(
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    key: &Key,
+    reason: &str,
+    retry: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut refusal = Message::new(
+        Some("Server".to_string()),
+        Some(get_timestamp()),
+        Some(reason.to_string()),
+        Some(SerdeColor::Red),
+    );
+    refusal.retry = retry.then_some(true);
+    let encrypted_refusal = encrypt_message(key, &refusal)?;
+    write_client_frame(&mut *writer.lock().await, &encrypted_refusal).await?;
+    Ok(())
+}
+
+// Claims the requested name, appending a counter until one isn't owned by another client
+fn claim_name(names: &DashMap<String, usize>, requested: &str, id: usize) -> String {
+    let mut name = requested.to_string();
+    let mut counter = 1;
+    loop {
+        match names.entry(name.clone()) {
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+                return name;
+            }
+            Entry::Occupied(entry) if *entry.get() == id => return name,
+            Entry::Occupied(_) => (),
+        }
+        name = format!("{}-{}", requested, counter);
+        counter += 1;
+    }
+}
+
+// Writes a frame to a client, counting the bytes sent
+async fn write_client_frame(
+    writer: &mut tokio::io::WriteHalf<TcpStream>,
+    data: &[u8],
+) -> std::io::Result<()> {
+    write_frame(writer, data).await?;
+    METRICS.bytes_out(data.len() + FRAME_HEADER_SIZE);
+    Ok(())
+}
+
 // Get the client ID
-async fn get_client_id(state: &SharedState) -> usize {
-    let state_guard = state.lock().await;
-    state_guard.len()
+fn get_client_id() -> usize {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
-// Assign a unique random color to each client
-async fn assign_random_color(
-    state: &SharedState,
+// Assign a unique color to each client, keeping the requested one if it is free
+async fn assign_color(
     assigned_colors: &AssignedColors, // External variable for assigned colors
-    id: &usize,
-) -> Result<SerdeColor, Box<dyn std::error::Error + Send + Sync>> {
-    let mut state_lock = state.lock().await;
+    requested: Option<SerdeColor>,
+) -> SerdeColor {
     let mut assigned_colors_lock = assigned_colors.lock().await;
 
-    let mut chosen_color = random_color();
+    let mut chosen_color = requested.unwrap_or_else(random_color);
 
     // Keep generating until a new unassigned color is found
     while assigned_colors_lock.contains(&chosen_color) {
         chosen_color = random_color();
     }
 
-    // Assign the color to the client in the state
-    if let Some(client) = state_lock.get_mut(id) {
-        client.color = chosen_color.clone();
-        assigned_colors_lock.insert(chosen_color.clone());
-    }
+    assigned_colors_lock.insert(chosen_color);
+    chosen_color
+}
 
-    Ok(chosen_color)
+// Checks an invite when someone joins with it, a single use invite is spent right away
+fn redeem_invite(invites: &DashMap<String, Invitation>, id: &str) -> Result<(), &'static str> {
+    let Entry::Occupied(entry) = invites.entry(id.to_string()) else {
+        return Err("This invite is not valid or was already used");
+    };
+    let invitation = entry.get();
+    if invitation
+        .expires
+        .is_some_and(|expires| expires <= Instant::now())
+    {
+        entry.remove();
+        return Err("This invite has expired");
+    }
+    if invitation.single_use {
+        entry.remove();
+    }
+    Ok(())
 }
 
 // Perform the handshake process
 async fn perform_handshake(
     key: &Key,
     reader: &mut BufReader<tokio::io::ReadHalf<TcpStream>>,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let mut buffer = [0u8; 1024];
-
-    match timeout(Duration::from_secs(60), reader.read(&mut buffer)).await {
-        Ok(Ok(n)) if n > 0 => {
-            let handshake = decrypt_handshake(&key, &buffer[..n])?;
-            Ok(handshake.name)
+    handshake_timeout: Duration,
+) -> Result<Handshake, Box<dyn std::error::Error + Send + Sync>> {
+    match timeout(handshake_timeout, read_frame(reader)).await {
+        Ok(Ok(Some(frame))) => {
+            METRICS.bytes_in(frame.len() + FRAME_HEADER_SIZE);
+            decrypt_handshake(key, &frame).map_err(|e| {
+                METRICS.decrypt_failure();
+                e.into()
+            })
+        }
+        Err(_) => {
+            METRICS.handshake_timeout();
+            warning!("Handshake timed out");
+            Err("Handshake timed out".into())
         }
         _ => {
-            eprintln!("Handshake failed or timed out");
+            warning!("Handshake failed or timed out");
             Err("Handshake failed or timed out".into())
         }
     }
 }
 
-// Spawn a task to send messages to the client
+// Ping the client every interval, returns once it has been silent for too long
+async fn run_heartbeat(
+    key: &Key,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    last_heard: &LastHeard,
+    heartbeat: HeartbeatConfig,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut ticker = interval(heartbeat.interval);
+
+    loop {
+        ticker.tick().await;
+
+        if last_heard.lock().await.elapsed() > heartbeat.timeout() {
+            return Ok(());
+        }
+
+        // A write that blocks for a whole interval means the connection is gone
+        let ping = send_server_message(writer, None, key, PING_SIGNAL, color);
+        if timeout(heartbeat.interval, ping).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Spawn a task to send messages to the client, telling the authors of direct messages once written
+// It stops when the connection breaks, or when the client falls behind under the disconnect policy
 fn spawn_message_sender(
     writer: Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
-    mut rx: broadcast::Receiver<Vec<u8>>,
+    mut rx: broadcast::Receiver<Outgoing>,
     name: &str,
+    key: Key,
+    state: SharedState,
+    policy: SlowClientPolicy,
 ) -> tokio::task::JoinHandle<()> {
     let name_clone = name.to_string();
     tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
+        loop {
+            let msg = match rx.recv().await {
+                Ok(msg) => msg,
+                // The queue was full and the oldest messages were dropped
+                Err(RecvError::Lagged(dropped)) => {
+                    METRICS.broadcast_lagged(dropped);
+                    warning!(
+                        "{} lagged behind, {} message(s) dropped",
+                        name_clone,
+                        dropped
+                    );
+                    let notice = match policy {
+                        SlowClientPolicy::Disconnect => format!(
+                            "Disconnected: your connection fell behind by {} message(s)",
+                            dropped
+                        ),
+                        _ => format!(
+                            "You missed {} message(s) because your connection fell behind",
+                            dropped
+                        ),
+                    };
+                    let sent =
+                        send_server_message(&writer, None, &key, &notice, SerdeColor::Red).await;
+                    if sent.is_err() || policy == SlowClientPolicy::Disconnect {
+                        break;
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
             let mut writer_lock = writer.lock().await;
-            if writer_lock.write_all(&msg).await.is_err() {
-                eprintln!("Failed to send message to {}: {:?}", name_clone, msg);
+            if write_client_frame(&mut writer_lock, &msg.frame)
+                .await
+                .is_err()
+            {
+                warning!("Failed to send message to {}", name_clone);
                 break;
             }
+            drop(writer_lock);
+            if let Some(delivery) = msg.delivery {
+                if let Err(e) = send_delivery_receipt(&key, &state, delivery) {
+                    warning!("Failed to send a delivery receipt: {:?}", e);
+                }
+            }
         }
     })
 }
 
+// Tells the author of a direct message it reached the recipient, if the author is still connected
+// Receipts are dropped rather than waited for, sender tasks waiting on each other would stall
+fn send_delivery_receipt(
+    key: &Key,
+    state: &SharedState,
+    delivery: Delivery,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(outbound) = state
+        .clients
+        .get(&delivery.author)
+        .map(|client| client.outbound.clone())
+    else {
+        return Ok(());
+    };
+    let receipt = delivery_receipt(delivery.recipient, delivery.message);
+    outbound.try_send(encrypt_message(key, &receipt)?.into());
+    Ok(())
+}
+
+// Tells the author the `target` message was written to `recipient`
+fn delivery_receipt(recipient: String, target: u64) -> Message {
+    let mut receipt = Message::new(
+        Some(recipient),
+        Some(get_timestamp()),
+        Some(DELIVERED_SIGNAL.to_string()),
+        None,
+    );
+    receipt.target = Some(target);
+    receipt
+}
+
 // Handle incoming messages from the client
+#[allow(clippy::too_many_arguments)]
 async fn handle_incoming_messages(
     key: &Key,
     state: &SharedState,
@@ -288,23 +948,102 @@ async fn handle_incoming_messages(
     sudo_key: SudoKey,
     color: SerdeColor,
     history: History,
+    last_heard: &LastHeard,
+    flood: &mut FloodGuard,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut buffer = [0u8; 1024];
+    // A closed connection (None) or a read error ends the session
+    while let Ok(Some(frame)) = read_frame(reader).await {
+        *last_heard.lock().await = Instant::now();
+        METRICS.bytes_in(frame.len() + FRAME_HEADER_SIZE);
+        let mut decrypted_msg = decrypt_message(key, &frame).inspect_err(|_| {
+            METRICS.decrypt_failure();
+        })?;
+
+        // Heartbeats only prove the client is alive, answer pings and move on
+        // Pings spend the flood budget like messages, the ones over it go unanswered
+        match decrypted_msg.message.as_deref() {
+            Some(PING_SIGNAL) => {
+                let exempt = config.rate_limits.exempt_sudo
+                    && state.clients.get(id).is_some_and(|client| client.sudo);
+                if exempt || check_flood(flood, state, name, 0, config).is_ok() {
+                    send_server_message(writer, None, key, PONG_SIGNAL, color).await?;
+                }
+                continue;
+            }
+            Some(PONG_SIGNAL) => continue,
+            _ => (),
+        }
+
+        // Anything else counts as activity for the idle time shown in /who
+        // The registered name is the one that counts, it changes with /nick
+        let (name, client_sudo, author) = match state.clients.get_mut(id) {
+            Some(mut client) => {
+                client.touch();
+                let author = client.author.clone().unwrap_or_default();
+                (client.name.clone(), client.sudo, author)
+            }
+            None => (name.to_string(), false, String::new()),
+        };
+        decrypted_msg.name = Some(name.clone());
+        // Only read receipts name a message, anything else the server sets is set again below
+        let read_target = decrypted_msg.target;
+        decrypted_msg.clear_server_fields();
+        decrypted_msg.author = Some(author.clone());
+
+        debug!("{}: {:?}", name, sensitive(&decrypted_msg));
+
+        // Read receipts have their own budget, each reader counts once per stored message
+        if decrypted_msg.message.as_deref() == Some(READ_SIGNAL) {
+            if let Some(target) = read_target.filter(|_| flood.reads.try_take()) {
+                record_read(key, state, &history, target, &name, &author).await?;
+            }
+            continue;
+        }
+
+        // Flooding is throttled and eventually muted, typing has its own cooldown
+        let exempt = client_sudo && config.rate_limits.exempt_sudo;
+        if !exempt && decrypted_msg.message.as_deref() != Some(TYPING_SIGNAL) {
+            let size = decrypted_msg.message.as_ref().map_or(0, String::len);
+            if let Err(notice) = check_flood(flood, state, &name, size, config) {
+                if check_cooldown(&mut flood.last_notice, Duration::from_secs(1)).is_ok() {
+                    send_server_message(writer, None, key, &notice, SerdeColor::Red).await?;
+                }
+                continue;
+            }
+        }
 
-    while let Ok(n) = reader.read(&mut buffer).await {
-        if n == 0 {
-            break; // Client disconnected
+        // Oversized messages are refused instead of stored and broadcast
+        let size = decrypted_msg
+            .message
+            .as_ref()
+            .map_or(0, |message| message.len());
+        if size > config.limits.max_message_size {
+            let notice = format!(
+                "Message too long ({} bytes), the limit is {} bytes",
+                size, config.limits.max_message_size
+            );
+            send_server_message(writer, None, key, &notice, SerdeColor::Red).await?;
+            continue;
         }
 
-        let decrypted_msg = decrypt_message(&key, &buffer[..n])?;
-        println!("{}: {:?}", name, decrypted_msg);
+        // Typing notifications are relayed to the others, at most once per cooldown
+        if decrypted_msg.message.as_deref() == Some(TYPING_SIGNAL) {
+            relay_typing(key, state, &name, id, color, config.typing_cooldown()).await?;
+            continue;
+        }
 
         // Handle the /sudo command
         if let Some(message) = &decrypted_msg.message {
+            if message.starts_with("/sudo") && !config.admin.sudo_enabled {
+                let notice = "Sudo is disabled on this server";
+                send_server_message(writer, None, key, notice, SerdeColor::Red).await?;
+                continue;
+            }
             if message.starts_with("/sudo") {
                 handle_sudo_command(
                     message,
-                    name,
+                    &name,
                     id,
                     sudo_key.clone(),
                     state,
@@ -317,38 +1056,64 @@ async fn handle_incoming_messages(
             }
         }
 
-        let client_sudo = {
-            let state_guard = state.lock().await;
-            state_guard
-                .get(id)
-                .map(|client| client.sudo)
-                .unwrap_or(false)
-        };
-
         // Handle sudo or non-sudo commands
         if let Some(message) = decrypted_msg.message.clone() {
             if client_sudo {
                 handle_sudo_commands(
                     &message,
-                    name,
+                    &name,
                     id,
                     state,
                     history.clone(),
                     key,
                     writer,
                     color,
+                    config,
                 )
                 .await?;
             } else {
-                handle_non_sudo_commands(&message, name, id, state, key, writer, color).await?;
+                handle_non_sudo_commands(
+                    &message, &name, id, state, &history, key, writer, color, config,
+                )
+                .await?;
             }
         }
 
-        // Broadcast non-command messages
+        // Replies and direct messages are stored like any other message once prepared
+        // Replies point at the one they answer, direct messages name their recipient
+        let command = decrypted_msg
+            .message
+            .as_deref()
+            .map(ServerCommand::from_str);
+        let prepared = match command {
+            Some(ServerCommand::Reply) => Some(prepare_reply(&mut decrypted_msg, &history).await),
+            Some(ServerCommand::Msg) => Some(prepare_direct_message(&mut decrypted_msg, state)),
+            _ => None,
+        };
+        if let Some(Err(problem)) = prepared {
+            send_server_message(writer, None, key, &problem, SerdeColor::Red).await?;
+            continue;
+        }
+
+        // Send non-command messages to whoever they are for, and tell the author they were stored
         if let Some(message) = &decrypted_msg.message {
-            if !message.starts_with("/") {
-                store_message_in_history(&decrypted_msg, id, state, history.clone()).await?;
-                broadcast_message(&key, &state, id, decrypted_msg).await?;
+            if prepared.is_some() || !message.starts_with("/") {
+                METRICS.message();
+                let max_messages = config.history.max_messages;
+                let stored_msg =
+                    store_message_in_history(key, decrypted_msg, id, state, &history, max_messages)
+                        .await?;
+                let mut receipt = Message::new(
+                    None,
+                    Some(get_timestamp()),
+                    Some(SENT_SIGNAL.to_string()),
+                    None,
+                );
+                receipt.target = stored_msg.id;
+                receipt.to = stored_msg.to.clone();
+                let encrypted_receipt = encrypt_message(key, &receipt)?;
+                write_client_frame(&mut *writer.lock().await, &encrypted_receipt).await?;
+                hold_for_offline(key, state, &stored_msg, writer, color).await?;
             }
         }
     }
@@ -356,7 +1121,57 @@ async fn handle_incoming_messages(
     Ok(())
 }
 
+// Spends the user's budget for a message, the error is the notice for a flooding or muted user
+fn check_flood(
+    flood: &mut FloodGuard,
+    state: &SharedState,
+    name: &str,
+    size: usize,
+    config: &Config,
+) -> Result<(), String> {
+    let now = Instant::now();
+    let muted_until = state.mutes.get(&flood.who).map(|until| *until);
+    match muted_until {
+        Some(until) if until > now => {
+            METRICS.message_throttled();
+            return Err(format!(
+                "You are muted for flooding, try again in {}",
+                format_duration(until - now + Duration::from_secs(1))
+            ));
+        }
+        Some(_) => {
+            state.mutes.remove(&flood.who);
+        }
+        None => (),
+    }
+
+    if flood.messages.try_take() && flood.bytes.try_take_n(size as f64) {
+        flood.throttled = 0;
+        return Ok(());
+    }
+
+    METRICS.message_throttled();
+    flood.throttled += 1;
+    if flood.throttled < config.rate_limits.mute_after {
+        return Err("You are sending messages too fast, slow down".to_string());
+    }
+
+    // Sustained flooding, the mute outlasts the connection so reconnecting doesn't lift it
+    flood.throttled = 0;
+    state
+        .mutes
+        .insert(flood.who.clone(), now + config.mute_duration());
+    METRICS.user_muted();
+    warning!("{} ({}) muted for flooding", name, flood.who);
+    flood.last_notice = None;
+    Err(format!(
+        "You have been muted for {} for flooding",
+        format_duration(config.mute_duration())
+    ))
+}
+
 // Handles the /sudo command
+#[allow(clippy::too_many_arguments)]
 async fn handle_sudo_command(
     message: &str,
     name: &str,
@@ -369,13 +1184,12 @@ async fn handle_sudo_command(
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let parts: Vec<&str> = message.split_whitespace().collect();
     if parts.len() == 2 && parts[1] == *sudo_key {
-        let mut state_guard = state.lock().await;
-        if let Some(client) = state_guard.get_mut(id) {
+        if let Some(mut client) = state.clients.get_mut(id) {
             client.sudo = true;
         }
 
         send_server_message(writer, None, key, SUDO_MESSAGE, color).await?;
-        println!("{} granted sudo privileges", name);
+        info!("{} granted sudo privileges", name);
     } else {
         send_server_message(
             writer,
@@ -385,7 +1199,7 @@ async fn handle_sudo_command(
             SerdeColor::Red,
         )
         .await?;
-        println!("{} provided incorrect sudo password", name);
+        warning!("{} provided incorrect sudo password", name);
     }
     Ok(())
 }
@@ -398,21 +1212,21 @@ async fn send_server_message(
     message: &str,
     color: SerdeColor,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let msg = Message {
-        name: Some(name.unwrap_or("Server").to_string()),
-        timestamp: Some(get_timestamp()),
-        message: Some(message.to_string()),
-        color: Some(color),
-    };
-
-    let encrypted_msg = encrypt_message(&key, &msg)?;
+    let msg = Message::new(
+        Some(name.unwrap_or("Server").to_string()),
+        Some(get_timestamp()),
+        Some(message.to_string()),
+        Some(color),
+    );
+
+    let encrypted_msg = encrypt_message(key, &msg)?;
     let mut writer_lock = writer.lock().await;
-    writer_lock.write_all(&encrypted_msg).await?;
-    writer_lock.flush().await?;
+    write_client_frame(&mut writer_lock, &encrypted_msg).await?;
     Ok(())
 }
 
 // Handles commands when sudo privileges are granted
+#[allow(clippy::too_many_arguments)]
 async fn handle_sudo_commands(
     message: &str,
     name: &str,
@@ -422,6 +1236,7 @@ async fn handle_sudo_commands(
     key: &Key,
     writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
     color: SerdeColor,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     match ServerCommand::from_str(message) {
         ServerCommand::Close => handle_close_command(name, key, writer, color).await?,
@@ -431,7 +1246,8 @@ async fn handle_sudo_commands(
         }
         ServerCommand::ViewHistory => {
             let name = "Your chat history: \n";
-            let global_messages = get_global_message_history(history).await;
+            let viewer = identity_of(state, id);
+            let global_messages = get_global_message_history(history, &viewer).await;
             send_server_message(writer, Some(name), key, &global_messages, color).await?;
         }
         ServerCommand::ViewKey => {
@@ -441,21 +1257,40 @@ async fn handle_sudo_commands(
             let color = change_client_color(id, state).await?;
             send_server_message(writer, None, key, "Color changed", color).await?;
         }
-        _ => (),
+        ServerCommand::Invite => match create_invite(command_argument(message), state, key) {
+            Ok(reply) => {
+                info!("{} created an invite", name);
+                send_server_message(writer, None, key, &reply, color).await?;
+            }
+            Err(problem) => {
+                send_server_message(writer, None, key, &problem, SerdeColor::Red).await?;
+            }
+        },
+        // Everything else behaves as for any other user
+        _ => {
+            handle_non_sudo_commands(
+                message, name, id, state, &history, key, writer, color, config,
+            )
+            .await?
+        }
     }
     Ok(())
 }
 
 // Handles non-sudo commands
+#[allow(clippy::too_many_arguments)]
 async fn handle_non_sudo_commands(
     message: &str,
     name: &str,
     id: &usize,
     state: &SharedState,
+    history: &History,
     key: &Key,
     writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
     color: SerdeColor,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cooldown = config.presence_cooldown();
     match ServerCommand::from_str(message) {
         ServerCommand::ViewMessages => {
             let name = "Your chat history: \n";
@@ -467,86 +1302,862 @@ async fn handle_non_sudo_commands(
             send_server_message(writer, None, key, "COLOR_CHANGE", color).await?;
         }
         ServerCommand::Close => handle_close_command(name, key, writer, color).await?,
+        ServerCommand::Who => send_user_list(id, state, key, writer, color).await?,
+        ServerCommand::Away => {
+            let reason = command_argument(message).to_string();
+            change_presence(
+                Presence::Away(reason),
+                name,
+                id,
+                state,
+                key,
+                writer,
+                color,
+                cooldown,
+            )
+            .await?
+        }
+        ServerCommand::Back => {
+            change_presence(
+                Presence::Back,
+                name,
+                id,
+                state,
+                key,
+                writer,
+                color,
+                cooldown,
+            )
+            .await?
+        }
+        ServerCommand::Status => {
+            let text = command_argument(message).to_string();
+            change_presence(
+                Presence::Status(text),
+                name,
+                id,
+                state,
+                key,
+                writer,
+                color,
+                cooldown,
+            )
+            .await?
+        }
+        ServerCommand::Nick => {
+            let requested = command_argument(message);
+            change_nickname(requested, name, id, state, key, writer, color, cooldown).await?
+        }
+        ServerCommand::Invite => {
+            let problem = "Only users with sudo privileges can create invites";
+            send_server_message(writer, None, key, problem, SerdeColor::Red).await?
+        }
+        ServerCommand::Edit => {
+            let argument = command_argument(message);
+            let (target, text) = argument
+                .split_once(char::is_whitespace)
+                .unwrap_or((argument, ""));
+            match Some(text.trim()).filter(|text| !text.is_empty()) {
+                Some(text) => {
+                    let change = Some(text.to_string());
+                    change_message(target, change, name, id, state, history, key, writer, color)
+                        .await?
+                }
+                None => {
+                    let usage = "Usage: /edit <id> <text>";
+                    send_server_message(writer, None, key, usage, SerdeColor::Red).await?
+                }
+            }
+        }
+        ServerCommand::Delete => {
+            let target = command_argument(message);
+            change_message(target, None, name, id, state, history, key, writer, color).await?
+        }
+        ServerCommand::React => {
+            let argument = command_argument(message);
+            react_to_message(argument, name, id, state, history, key, writer).await?
+        }
+        ServerCommand::Thread => {
+            let thread = match command_argument(message).trim_start_matches('#').parse() {
+                Ok(target) => get_thread(history, target, &identity_of(state, id)).await,
+                Err(_) => "Usage: /thread <id>".to_string(),
+            };
+            send_server_message(writer, Some("Thread: \n"), key, &thread, color).await?
+        }
+        ServerCommand::Mentions => {
+            let found = get_mentions(history, name, &identity_of(state, id)).await;
+            send_server_message(writer, Some("Your mentions: \n"), key, &found, color).await?
+        }
+        ServerCommand::Search => {
+            let results = match SearchQuery::parse(command_argument(message)) {
+                Ok(query) => search_history(history, &identity_of(state, id), &query).await,
+                Err(problem) => problem,
+            };
+            send_server_message(writer, Some("Search results: \n"), key, &results, color).await?
+        }
         _ => (),
     }
     Ok(())
 }
 
-// Handles the /close command
-async fn handle_close_command(
+// Creates an invite for `/invite [minutes] [once]`, the reply holds the token or what was wrong
+fn create_invite(argument: &str, state: &SharedState, key: &Key) -> Result<String, String> {
+    let now = Instant::now();
+    let mut expires = None;
+    let mut single_use = false;
+    for word in argument.split_whitespace() {
+        match (word, word.parse::<u64>()) {
+            ("once", _) => single_use = true,
+            (_, Ok(minutes)) if minutes > 0 && expires.is_none() => {
+                let lifetime = Duration::from_secs(minutes.saturating_mul(60));
+                expires = Some(now.checked_add(lifetime).ok_or(INVITE_USAGE)?);
+            }
+            _ => return Err(INVITE_USAGE.to_string()),
+        }
+    }
+
+    // Expired invites are otherwise only dropped when someone tries them
+    state
+        .invites
+        .retain(|_, invitation| invitation.expires.is_none_or(|expires| expires > now));
+    if state.invites.len() >= MAX_INVITES {
+        return Err(format!(
+            "There are already {} open invites, wait for some to expire",
+            MAX_INVITES
+        ));
+    }
+
+    let (host, port) = state.public_address.clone();
+    let invite = Invite {
+        host,
+        port,
+        key: key.to_string(),
+        id: generate_key(INVITE_ID_SIZE),
+    };
+    let token = invite.to_token().map_err(|e| e.to_string())?;
+    state.invites.insert(
+        invite.id,
+        Invitation {
+            expires,
+            single_use,
+        },
+    );
+
+    let mut details = Vec::new();
+    if let Some(expires) = expires {
+        details.push(format!("expires in {}", format_duration(expires - now)));
+    }
+    if single_use {
+        details.push("single use".to_string());
+    }
+    match details.is_empty() {
+        true => Ok(format!("Invite: {}", token)),
+        false => Ok(format!("Invite ({}): {}", details.join(", "), token)),
+    }
+}
+
+// Edits (Some text) or deletes (None) a stored message, for its author or a user with sudo
+// The author is whoever sent it with the same identity, the change reaches everyone
+#[allow(clippy::too_many_arguments)]
+async fn change_message(
+    target: &str,
+    text: Option<String>,
     name: &str,
+    id: &usize,
+    state: &SharedState,
+    history: &History,
     key: &Key,
     writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
     color: SerdeColor,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("{} issued /close command", name);
+    let Ok(target) = target.trim_start_matches('#').parse::<u64>() else {
+        let usage = match text {
+            Some(_) => "Usage: /edit <id> <text>",
+            None => "Usage: /delete <id>",
+        };
+        send_server_message(writer, None, key, usage, SerdeColor::Red).await?;
+        return Ok(());
+    };
 
-    send_server_message(writer, None, key, "CLOSE_CONNECTION", color).await?;
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    let editor = match state.clients.get(id) {
+        Some(client) if client.sudo => Editor::Sudo,
+        Some(client) => Editor::Author(client.author.clone().unwrap_or_default()),
+        None => return Ok(()),
+    };
+
+    let action = if text.is_some() { "edited" } else { "deleted" };
+    let viewer = identity_of(state, id);
+    match rewrite_message(history, state, target, text, &viewer, &editor).await {
+        Ok((notice, participants)) => {
+            info!("{} {} message {}", name, action, target);
+            send_to_audience(key, state, id, notice, participants).await?;
+            let done = format!("Message #{} {}", target, action);
+            send_server_message(writer, None, key, &done, color).await?;
+        }
+        Err(problem) => {
+            send_server_message(writer, None, key, problem, SerdeColor::Red).await?;
+        }
+    }
     Ok(())
 }
 
-// Fetches the client's message history
-async fn get_client_message_history(id: &usize, state: &SharedState) -> String {
-    let state_guard = state.lock().await;
-    if let Some(client) = state_guard.get(id) {
-        return match client.get_messages() {
-            Ok(messages) => messages,
+// Changes a message in history and in its author's messages, `viewer` is the editor's identity
+// The result is the notice, for everyone or for both sides of a direct message
+// Deleted messages stay in history without their text, so their ID is never handed out again
+async fn rewrite_message(
+    history: &History,
+    state: &SharedState,
+    target: u64,
+    text: Option<String>,
+    viewer: &str,
+    editor: &Editor,
+) -> Result<(Message, Option<Vec<String>>), &'static str> {
+    let edited = Some(get_timestamp());
+    let original = {
+        let mut history_guard = history.lock().await;
+        let stored = history_guard
+            .iter_mut()
+            .find(|msg| msg.id == Some(target) && msg.visible_to(viewer))
+            .ok_or("There is no such message, it may be too old")?;
+        if !matches!(editor, Editor::Sudo) && !editor.wrote(stored) {
+            return Err("You can only change your own messages");
+        }
+        if stored.is_deleted() {
+            return Err("This message was deleted");
+        }
+        stored.message = text.clone();
+        stored.edited = edited.clone();
+        if text.is_none() {
+            stored.reactions = None;
+        }
+        stored.clone()
+    };
+
+    for mut client in state.clients.iter_mut() {
+        if let Some(own) = client
+            .messages
+            .iter_mut()
+            .find(|msg| msg.id == Some(target))
+        {
+            own.message = text.clone();
+            own.edited = edited.clone();
+            own.reactions = original.reactions.clone();
+        }
+    }
+
+    let participants = original.participants();
+    let mut notice = Message::new(
+        original.name,
+        Some(get_timestamp()),
+        Some(text.unwrap_or_else(|| DELETE_SIGNAL.to_string())),
+        original.color,
+    );
+    notice.target = Some(target);
+    notice.edited = edited;
+    Ok((notice, participants))
+}
+
+// Adds a reaction to a stored message, or takes it back when the user already reacted with it
+// Everyone gets the new counts, the user who reacted included
+async fn react_to_message(
+    argument: &str,
+    name: &str,
+    id: &usize,
+    state: &SharedState,
+    history: &History,
+    key: &Key,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let parsed = argument
+        .split_once(char::is_whitespace)
+        .and_then(|(target, emoji)| {
+            let target = target.trim_start_matches('#').parse::<u64>().ok()?;
+            let emoji = emoji.trim();
+            let valid =
+                emoji.chars().count() <= MAX_REACTION_SIZE && !emoji.contains(char::is_whitespace);
+            valid.then_some((target, emoji))
+        });
+    let Some((target, emoji)) = parsed else {
+        let usage = "Usage: /react <id> <emoji>";
+        send_server_message(writer, None, key, usage, SerdeColor::Red).await?;
+        return Ok(());
+    };
+
+    let viewer = identity_of(state, id);
+    match toggle_reaction(history, state, target, emoji, name, &viewer).await {
+        Ok((update, participants)) => {
+            let encrypted_update = encrypt_message(key, &update)?;
+            write_client_frame(&mut *writer.lock().await, &encrypted_update).await?;
+            send_to_audience(key, state, id, update, participants).await?;
+        }
+        Err(problem) => {
+            send_server_message(writer, None, key, problem, SerdeColor::Red).await?;
+        }
+    }
+    Ok(())
+}
+
+// Updates the reactions in history and in the author's messages, `viewer` is the user's identity
+// The result is the update to send, and both sides of a direct message
+async fn toggle_reaction(
+    history: &History,
+    state: &SharedState,
+    target: u64,
+    emoji: &str,
+    name: &str,
+    viewer: &str,
+) -> Result<(Message, Option<Vec<String>>), &'static str> {
+    let stored = {
+        let mut history_guard = history.lock().await;
+        let stored = history_guard
+            .iter_mut()
+            .find(|msg| msg.id == Some(target) && msg.visible_to(viewer))
+            .ok_or("There is no such message, it may be too old")?;
+        if stored.is_deleted() {
+            return Err("This message was deleted");
+        }
+
+        let reactions = stored.reactions.get_or_insert_with(BTreeMap::new);
+        if !reactions.contains_key(emoji) && reactions.len() >= MAX_REACTIONS {
+            return Err("This message already has too many different reactions");
+        }
+        let names = reactions.entry(emoji.to_string()).or_default();
+        match names.iter().position(|reacted| reacted == name) {
+            Some(index) => {
+                names.remove(index);
+            }
+            None => names.push(name.to_string()),
+        }
+        if names.is_empty() {
+            reactions.remove(emoji);
+        }
+        if reactions.is_empty() {
+            stored.reactions = None;
+        }
+        stored.clone()
+    };
+
+    for mut client in state.clients.iter_mut() {
+        if let Some(own) = client
+            .messages
+            .iter_mut()
+            .find(|msg| msg.id == Some(target))
+        {
+            own.reactions = stored.reactions.clone();
+        }
+    }
+
+    let mut update = Message::new(
+        Some(name.to_string()),
+        Some(get_timestamp()),
+        Some(REACTION_SIGNAL.to_string()),
+        stored.color,
+    );
+    update.target = Some(target);
+    update.quote = Some(format!(
+        "{}: {}",
+        stored.name.as_deref().unwrap_or("Unknown"),
+        snippet(stored.message.as_deref().unwrap_or(""), QUOTE_SIZE)
+    ));
+    update.reactions = stored.reactions.clone();
+    Ok((update, stored.participants()))
+}
+
+// Turns `/reply <id> <text>` into a message answering `id`, quoting the start of it
+// Replies to a direct message go to the other side only
+async fn prepare_reply(msg: &mut Message, history: &History) -> Result<(), String> {
+    let command = msg.message.clone().unwrap_or_default();
+    let usage = "Usage: /reply <id> <text>";
+    let (target, text) = command_argument(&command)
+        .split_once(char::is_whitespace)
+        .ok_or(usage)?;
+    let target = target
+        .trim_start_matches('#')
+        .parse::<u64>()
+        .map_err(|_| usage)?;
+
+    let author = msg.author.clone().unwrap_or_default();
+    let history_guard = history.lock().await;
+    let original = history_guard
+        .iter()
+        .find(|stored| stored.id == Some(target) && stored.visible_to(&author))
+        .ok_or("There is no such message, it may be too old")?;
+    if original.is_deleted() {
+        return Err("This message was deleted".to_string());
+    }
+    if let Some((to, recipient)) = original.other_side(&author) {
+        msg.to = Some(to.to_string());
+        msg.recipient = Some(recipient.to_string());
+    }
+    msg.quote = Some(format!(
+        "{}: {}",
+        original.name.as_deref().unwrap_or("Unknown"),
+        snippet(original.message.as_deref().unwrap_or(""), QUOTE_SIZE)
+    ));
+    msg.reply_to = Some(target);
+    msg.message = Some(text.trim().to_string());
+    Ok(())
+}
+
+// Turns `/msg <name> <text>` into a direct message, only sent to someone connected
+fn prepare_direct_message(msg: &mut Message, state: &SharedState) -> Result<(), String> {
+    let command = msg.message.clone().unwrap_or_default();
+    let usage = "Usage: /msg <name> <text>";
+    let (recipient, text) = command_argument(&command)
+        .split_once(char::is_whitespace)
+        .ok_or(usage)?;
+    if text.trim().is_empty() {
+        return Err(usage.to_string());
+    }
+    if msg.name.as_deref() == Some(recipient) {
+        return Err("You can't send a direct message to yourself".to_string());
+    }
+    // Only the identity using the name now gets it, and sees it later
+    let connected = state.names.get(recipient).map(|id| *id);
+    let identity = connected
+        .and_then(|id| state.clients.get(&id)?.author.clone())
+        .or_else(|| state.offline.owner(recipient));
+    let Some(identity) = identity else {
+        return Err(format!("{} is not connected", recipient));
+    };
+    msg.to = Some(recipient.to_string());
+    msg.recipient = Some(identity);
+    msg.message = Some(text.trim().to_string());
+    Ok(())
+}
+
+// The conversation around a message, one line per message indented under the one it answers
+// Direct messages only show up for the identities on their two sides
+async fn get_thread(history: &History, target: u64, viewer: &str) -> String {
+    let history_guard = history.lock().await;
+    let thread = collect_thread(&history_guard, target);
+    if !thread.iter().any(|(_, msg)| msg.visible_to(viewer)) {
+        return "There is no such message, it may be too old".to_string();
+    }
+    thread
+        .into_iter()
+        .filter(|(_, msg)| msg.visible_to(viewer))
+        .map(|(depth, msg)| {
+            let indent = "  ".repeat(depth);
+            let id = msg.id.unwrap_or_default();
+            match msg.message.as_deref() {
+                None => format!("{}#{} [deleted]", indent, id),
+                Some(text) => format!(
+                    "{}#{} {}: {}{}{}",
+                    indent,
+                    id,
+                    msg.sender_label(),
+                    text,
+                    msg.edited_mark(),
+                    msg.reaction_counts()
+                ),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// The latest messages in history that mention `name` and that their identity can see,
+// oldest first
+async fn get_mentions(history: &History, name: &str, viewer: &str) -> String {
+    let history_guard = history.lock().await;
+    let mut found: Vec<String> = history_guard
+        .iter()
+        .rev()
+        .filter(|msg| msg.visible_to(viewer))
+        .filter_map(|msg| {
+            let text = msg.message.as_deref()?;
+            mentions(text).contains(&name).then(|| history_line(msg))
+        })
+        .take(MENTIONS_SHOWN)
+        .collect();
+    if found.is_empty() {
+        return "Nobody mentioned you in the stored history".to_string();
+    }
+    found.reverse();
+    found.join("\n")
+}
+
+// Stored messages the `viewer` identity can see that match the query, newest first, one page
+// at a time
+async fn search_history(history: &History, viewer: &str, query: &SearchQuery) -> String {
+    let history_guard = history.lock().await;
+    let found: Vec<&Message> = history_guard
+        .iter()
+        .rev()
+        .filter(|msg| msg.visible_to(viewer) && query.matches(msg))
+        .collect();
+    if found.is_empty() {
+        return "No messages found".to_string();
+    }
+    let pages = found.len().div_ceil(SEARCH_PAGE_SIZE);
+    if query.page > pages {
+        return format!("There are only {} page(s) of results", pages);
+    }
+
+    let mut lines: Vec<String> = found
+        .iter()
+        .skip((query.page - 1) * SEARCH_PAGE_SIZE)
+        .take(SEARCH_PAGE_SIZE)
+        .map(|msg| history_line(msg))
+        .collect();
+    let mut footer = format!(
+        "Page {} of {}, {} message(s) found",
+        query.page,
+        pages,
+        found.len()
+    );
+    if query.page < pages {
+        footer.push_str(&format!(", add page:{} for more", query.page + 1));
+    }
+    lines.push(footer);
+    lines.join("\n")
+}
+
+// Handles the /close command
+async fn handle_close_command(
+    name: &str,
+    key: &Key,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("{} issued /close command", name);
+
+    send_server_message(writer, None, key, "CLOSE_CONNECTION", color).await?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    Ok(())
+}
+
+// Sends the list of connected users, each one in its own color
+async fn send_user_list(
+    id: &usize,
+    state: &SharedState,
+    key: &Key,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let users = user_list(id, state);
+    let header = format!("{} user(s) online:", users.len());
+    send_server_message(writer, None, key, &header, color).await?;
+    for (line, user_color) in users {
+        send_server_message(writer, None, key, &line, user_color).await?;
+    }
+    Ok(())
+}
+
+// One line per connected user for /who, sorted by name, `id` is marked as the one asking
+fn user_list(id: &usize, state: &SharedState) -> Vec<(String, SerdeColor)> {
+    let mut users: Vec<(String, SerdeColor)> = {
+        state
+            .clients
+            .iter()
+            .map(|entry| {
+                let client = entry.value();
+                let mut line = client.name.clone();
+                if entry.key() == id {
+                    line.push_str(" (you)");
+                }
+                line.push_str(&format!(" - idle {}", format_duration(client.idle_time())));
+                match client.away.as_deref() {
+                    Some("") => line.push_str(" - away"),
+                    Some(reason) => line.push_str(&format!(" - away ({})", reason)),
+                    None => (),
+                }
+                if let Some(status) = &client.status {
+                    line.push_str(&format!(" - \"{}\"", status));
+                }
+                (line, client.color)
+            })
+            .collect()
+    };
+    users.sort_by(|a, b| a.0.cmp(&b.0));
+    users
+}
+
+// Applies an away/back/status change and announces it, unless the user changes it too often
+#[allow(clippy::too_many_arguments)]
+async fn change_presence(
+    presence: Presence,
+    name: &str,
+    id: &usize,
+    state: &SharedState,
+    key: &Key,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    color: SerdeColor,
+    cooldown: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match update_presence(presence, name, id, state, cooldown) {
+        Ok(Some(event)) => {
+            send_server_message(writer, None, key, &event, color).await?;
+            broadcast_presence(key, state, id, &event, color).await
+        }
+        Ok(None) => Ok(()),
+        Err(notice) => send_server_message(writer, None, key, &notice, SerdeColor::Red).await,
+    }
+}
+
+// Changes the user's presence, the result is the event to announce, None once they are gone
+// The error is the notice for a user changing it too often
+fn update_presence(
+    presence: Presence,
+    name: &str,
+    id: &usize,
+    state: &SharedState,
+    cooldown: Duration,
+) -> Result<Option<String>, String> {
+    let Some(mut client) = state.clients.get_mut(id) else {
+        return Ok(None);
+    };
+
+    if let Err(wait) = check_cooldown(&mut client.last_presence_change, cooldown) {
+        return Err(format!(
+            "You are changing your status too often, try again in {}",
+            format_duration(wait + Duration::from_secs(1))
+        ));
+    }
+
+    let event = match presence {
+        Presence::Away(reason) => {
+            let event = match reason.as_str() {
+                "" => format!("{} is away", name),
+                reason => format!("{} is away: {}", name, reason),
+            };
+            client.away = Some(reason);
+            event
+        }
+        Presence::Back => {
+            client.away = None;
+            format!("{} is back", name)
+        }
+        Presence::Status(text) if text.is_empty() => {
+            client.status = None;
+            format!("{} cleared their status", name)
+        }
+        Presence::Status(text) => {
+            let event = format!("{} set their status: {}", name, text);
+            client.status = Some(text);
+            event
+        }
+    };
+    Ok(Some(event))
+}
+
+// Renames the user with the same uniqueness rules as when joining and announces it
+#[allow(clippy::too_many_arguments)]
+async fn change_nickname(
+    requested: &str,
+    name: &str,
+    id: &usize,
+    state: &SharedState,
+    key: &Key,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    color: SerdeColor,
+    cooldown: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if requested.is_empty() || requested.contains(char::is_whitespace) {
+        let usage = "Usage: /nick <newname> (no spaces)";
+        return send_server_message(writer, None, key, usage, SerdeColor::Red).await;
+    }
+    if requested == name {
+        let notice = format!("You are already {}", name);
+        return send_server_message(writer, None, key, &notice, color).await;
+    }
+
+    let cooldown_result = match state.clients.get_mut(id) {
+        Some(mut client) => check_cooldown(&mut client.last_presence_change, cooldown),
+        None => return Ok(()),
+    };
+    if let Err(wait) = cooldown_result {
+        let notice = format!(
+            "You are changing your name too often, try again in {}",
+            format_duration(wait + Duration::from_secs(1))
+        );
+        return send_server_message(writer, None, key, &notice, SerdeColor::Red).await;
+    }
+
+    // The new name is claimed before the old one is released, so nobody can take it in between
+    let new_name = claim_name(&state.names, requested, *id);
+    if let Some(mut client) = state.clients.get_mut(id) {
+        client.name = new_name.clone();
+    }
+    state.names.remove_if(name, |_, owner| owner == id);
+
+    // The signal carries the new name so the client can update its instance
+    send_server_message(writer, Some(&new_name), key, NAME_CHANGE_SIGNAL, color).await?;
+
+    let event = format!("{} is now known as {}", name, new_name);
+    info!("{}", event);
+    broadcast_presence(key, state, id, &event, color).await
+}
+
+// Tells the others that the user is typing, dropping notifications sent too often
+async fn relay_typing(
+    key: &Key,
+    state: &SharedState,
+    name: &str,
+    id: &usize,
+    color: SerdeColor,
+    cooldown: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let allowed = match state.clients.get_mut(id) {
+        Some(mut client) => check_cooldown(&mut client.last_typing, cooldown).is_ok(),
+        None => false,
+    };
+    if !allowed {
+        return Ok(());
+    }
+
+    let typing_msg = Message::new(
+        Some(name.to_string()),
+        Some(get_timestamp()),
+        Some(TYPING_SIGNAL.to_string()),
+        Some(color),
+    );
+    broadcast_message(key, state, id, typing_msg).await
+}
+
+// Fetches the client's message history
+async fn get_client_message_history(id: &usize, state: &SharedState) -> String {
+    if let Some(client) = state.clients.get(id) {
+        return match client.get_messages() {
+            Ok(messages) => messages,
             Err(_) => "No message history".to_string(),
         };
     }
     format!("Couldn't get client with id {}", id)
 }
 
-// Fetches the global message history
-async fn get_global_message_history(history: History) -> String {
+// Fetches the global message history, with the direct messages the `viewer` identity sent or
+// received
+async fn get_global_message_history(history: History, viewer: &str) -> String {
     let history_guard = history.lock().await;
     history_guard
         .iter()
-        .map(|msg| {
-            format!(
-                "{}: {}: {}",
-                msg.name.clone().unwrap_or("Unknown".to_string()),
-                msg.timestamp.clone().unwrap_or("Unknown time".to_string()),
-                msg.message.as_deref().unwrap_or("")
-            )
-        })
+        .filter(|msg| !msg.is_deleted() && msg.visible_to(viewer))
+        .map(history_line)
         .collect::<Vec<String>>()
         .join("\n")
 }
 
+// One stored message as listed by /view-history, /mentions and /search
+fn history_line(msg: &Message) -> String {
+    format!(
+        "#{} {}: {}: {}{}{}",
+        msg.id.unwrap_or_default(),
+        msg.sender_label(),
+        msg.timestamp.as_deref().unwrap_or("Unknown time"),
+        msg.message.as_deref().unwrap_or(""),
+        msg.edited_mark(),
+        msg.reaction_counts()
+    )
+}
+
 // Changes the client's color
 async fn change_client_color(
     id: &usize,
     state: &SharedState,
 ) -> Result<SerdeColor, Box<dyn std::error::Error + Send + Sync>> {
-    let mut state_guard = state.lock().await;
     let color = random_color();
-    if let Some(client) = state_guard.get_mut(id) {
+    if let Some(mut client) = state.clients.get_mut(id) {
         client.color = color;
     }
     Ok(color)
 }
 
-// Stores the message in both client and global history
+// Stores the message in both client and global history, assigning it the next message ID
+// It is handed to the dispatcher before the history lock is released, so IDs reach every client
+// in order, and nothing waits for a slow client with the lock held
+// Both histories keep at most `max_messages`, dropping the oldest ones first
 async fn store_message_in_history(
-    message: &Message,
+    key: &Key,
+    mut message: Message,
     id: &usize,
     state: &SharedState,
-    history: History,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut state_guard = state.lock().await;
-    if let Some(client) = state_guard.get_mut(id) {
+    history: &History,
+    max_messages: usize,
+) -> Result<Message, Box<dyn std::error::Error + Send + Sync>> {
+    let permit = state.dispatch.reserve().await?;
+    let mut history_guard = history.lock().await;
+    message.id = Some(latest_message_id(&history_guard) + 1);
+
+    if let Some(mut client) = state.clients.get_mut(id) {
+        message.author = client.author.clone();
         client.add_message(message.clone());
+        while client.messages.len() > max_messages {
+            client.messages.pop_front();
+        }
     }
-    history.lock().await.push_back(message.clone());
-    Ok(())
+    history_guard.push_back(message.clone());
+    while history_guard.len() > max_messages {
+        history_guard.pop_front();
+    }
+
+    let (outbounds, delivery) = match message.to.clone() {
+        Some(recipient) => {
+            let delivery = Delivery {
+                author: *id,
+                message: message.id.unwrap_or_default(),
+                recipient: recipient.clone(),
+            };
+            let outbound = message
+                .recipient
+                .as_deref()
+                .and_then(|recipient| outbound_of_identity(state, recipient));
+            (outbound.into_iter().collect(), Some(delivery))
+        }
+        None => (outbounds_except(state, id), None),
+    };
+    permit.send(Dispatch {
+        frame: encrypt_message(key, &message)?.into(),
+        outbounds,
+        delivery,
+    });
+    drop(history_guard);
+    Ok(message)
+}
+
+// Queues stored messages for their recipients one at a time, so IDs reach every client in order
+// Under the block policy this task waits for slow clients, storing only waits once it is full
+async fn dispatch_messages(mut rx: mpsc::Receiver<Dispatch>) {
+    while let Some(dispatch) = rx.recv().await {
+        for outbound in dispatch.outbounds {
+            let (frame, delivery) = (dispatch.frame.clone(), dispatch.delivery.clone());
+            outbound.send_tracked(frame, delivery).await;
+        }
+    }
+}
+
+// ID of the newest message in history, 0 when nothing has been stored yet
+fn latest_message_id(history: &VecDeque<Message>) -> u64 {
+    history.back().and_then(|msg| msg.id).unwrap_or(0)
 }
 
 // Clean up the client on disconnect
-async fn cleanup_client(state: SharedState, name: &str, id: &usize) {
-    state.lock().await.remove(id);
-    println!("{} disconnected", name);
+async fn cleanup_client(
+    key: &Key,
+    state: SharedState,
+    assigned_colors: &AssignedColors,
+    name: &str,
+    id: &usize,
+) {
+    let removed = state.clients.remove(id);
+    // The client may have been renamed since it joined
+    let name = removed
+        .as_ref()
+        .map_or(name, |(_, client)| client.name.as_str());
+    state.names.remove_if(name, |_, owner| owner == id);
+    if let Some((_, client)) = &removed {
+        METRICS.client_disconnected();
+        // Release the color so the client can get it back when it reconnects
+        assigned_colors.lock().await.remove(&client.color);
+
+        let online = state.clients.len();
+        let left = format!("{} left the chat ({} online)", name, online);
+        if let Err(e) = broadcast_presence(key, &state, id, &left, client.color).await {
+            warning!("Failed to announce that {} left: {:?}", name, e);
+        }
+    }
+    info!("{} disconnected", name);
 }
 
 // Send the handshake response to the client with a color
@@ -555,13 +2166,14 @@ async fn send_handshake_response(
     name: &str,
     writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
     color: SerdeColor,
+    latest: u64,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let handshake = Handshake::new(name.to_string(), 1024, Some(color));
-    let encrypted_handshake = encrypt_handshake(&key, &handshake)?;
+    let mut handshake = Handshake::new(name.to_string(), 1024, Some(color));
+    handshake.last_seen = Some(latest);
+    let encrypted_handshake = encrypt_handshake(key, &handshake)?;
 
     let mut writer_lock = writer.lock().await;
-    writer_lock.write_all(&encrypted_handshake).await?;
-    writer_lock.flush().await?;
+    write_client_frame(&mut writer_lock, &encrypted_handshake).await?;
     Ok(())
 }
 
@@ -571,40 +2183,772 @@ async fn send_welcome_message(
     name: &str,
     writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
     color: SerdeColor,
+    state: &SharedState,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let welcome_msg = Message {
-        name: Some("Server".to_string()),
-        timestamp: Some(get_timestamp()),
-        message: Some(format!(
-            "Welcome {} to the chat! Use /help for available commands",
-            name
+    let online = state.clients.len();
+    let welcome_msg = Message::new(
+        Some("Server".to_string()),
+        Some(get_timestamp()),
+        Some(format!(
+            "Welcome {} to the chat! {} user(s) online. Use /help for available commands",
+            name, online
         )),
-        color: Some(color),
-    };
+        Some(color),
+    );
 
-    let encrypted_msg = encrypt_message(&key, &welcome_msg)?;
+    let encrypted_msg = encrypt_message(key, &welcome_msg)?;
 
     let mut writer_lock = writer.lock().await;
-    writer_lock.write_all(&encrypted_msg).await?;
-    writer_lock.flush().await?;
+    write_client_frame(&mut writer_lock, &encrypted_msg).await?;
+    Ok(())
+}
+
+// Send a resuming client the messages stored after the last one it saw, up to the latest one
+// when it registered, the later ones are already in its queue
+#[allow(clippy::too_many_arguments)]
+async fn send_missed_messages(
+    key: &Key,
+    name: &str,
+    author: &str,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    history: &History,
+    last_seen: u64,
+    latest: u64,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // The client's own messages are skipped, it already has them
+    let missed_messages: Vec<Message> = {
+        let history_guard = history.lock().await;
+        history_guard
+            .iter()
+            .filter(|msg| {
+                msg.id
+                    .is_some_and(|msg_id| msg_id > last_seen && msg_id <= latest)
+            })
+            .filter(|msg| msg.name.as_deref() != Some(name) && !msg.is_deleted())
+            .filter(|msg| msg.visible_to(author))
+            .cloned()
+            .collect()
+    };
+
+    if missed_messages.is_empty() {
+        return Ok(());
+    }
+
+    let notice = format!(
+        "You missed {} message(s) while disconnected",
+        missed_messages.len()
+    );
+    send_server_message(writer, None, key, &notice, color).await?;
+
+    for msg in missed_messages {
+        let encrypted_msg = encrypt_message(key, &msg)?;
+        let mut writer_lock = writer.lock().await;
+        write_client_frame(&mut writer_lock, &encrypted_msg).await?;
+    }
+
+    info!("{} resumed the session after message {}", name, last_seen);
+    Ok(())
+}
+
+// Records that `reader` saw a stored message and tells its author, once per reader identity
+// The receipt goes to the identity that wrote it, messages to the room also tell how many have
+// read them
+async fn record_read(
+    key: &Key,
+    state: &SharedState,
+    history: &History,
+    target: u64,
+    reader: &str,
+    reader_identity: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (author, receipt) = {
+        let mut history_guard = history.lock().await;
+        let Some(stored) = history_guard
+            .iter_mut()
+            .find(|msg| msg.id == Some(target) && msg.visible_to(reader_identity))
+        else {
+            return Ok(());
+        };
+        let Some(author) = stored.author.clone() else {
+            return Ok(());
+        };
+        let already_read = stored.read_by.iter().any(|read| read == reader_identity);
+        if author == reader_identity || stored.is_deleted() || already_read {
+            return Ok(());
+        }
+        stored.read_by.push(reader_identity.to_string());
+
+        let mut receipt = Message::new(
+            Some(reader.to_string()),
+            Some(get_timestamp()),
+            Some(READ_SIGNAL.to_string()),
+            None,
+        );
+        receipt.target = Some(target);
+        if stored.to.is_none() {
+            receipt.seen_by = Some(stored.read_by.len());
+        }
+        (author, receipt)
+    };
+    send_receipt(key, state, &author, &receipt)
+}
+
+// Queues a message for whoever is connected with the identity `author`, false when nobody is
+async fn send_to_identity(
+    key: &Key,
+    state: &SharedState,
+    author: &str,
+    msg: &Message,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(outbound) = outbound_of_identity(state, author) else {
+        return Ok(false);
+    };
+    let frame: Frame = encrypt_message(key, msg)?.into();
+    outbound.send(frame).await;
+    Ok(true)
+}
+
+// Queues a receipt for whoever is connected with the identity `author` if there is room, it is
+// never waited for
+fn send_receipt(
+    key: &Key,
+    state: &SharedState,
+    author: &str,
+    receipt: &Message,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(outbound) = outbound_of_identity(state, author) {
+        outbound.try_send(encrypt_message(key, receipt)?.into());
+    }
+    Ok(())
+}
+
+// Sends a message to everyone but the sender, or only to the identities on both sides of a
+// direct message
+async fn send_to_audience(
+    key: &Key,
+    state: &SharedState,
+    sender_id: &usize,
+    msg: Message,
+    participants: Option<Vec<String>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(participants) = participants else {
+        return broadcast_message(key, state, sender_id, msg).await;
+    };
+    let sender = identity_of(state, sender_id);
+    for author in participants {
+        if author != sender {
+            send_to_identity(key, state, &author, &msg).await?;
+        }
+    }
+    Ok(())
+}
+
+// Holds a stored message for whoever it is for and isn't connected: the recipient of a direct
+// message, or the users mentioned in a message to the room. The author is told who gets it later
+async fn hold_for_offline(
+    key: &Key,
+    state: &SharedState,
+    msg: &Message,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let recipients = match (&msg.to, &msg.message) {
+        (Some(to), _) => vec![to.as_str()],
+        (None, Some(text)) => mentions(text),
+        (None, None) => Vec::new(),
+    };
+    let mut held = Vec::new();
+    for recipient in recipients {
+        // A direct message waits for the identity it was sent to, whatever name it uses now
+        let away = match &msg.recipient {
+            Some(identity) => outbound_of_identity(state, identity).is_none(),
+            None => !state.names.contains_key(recipient),
+        };
+        if away && msg.name.as_deref() != Some(recipient) && state.offline.push(recipient, msg)? {
+            held.push(recipient);
+        }
+    }
+    if held.is_empty() {
+        return Ok(());
+    }
+
+    if let Err(e) = state.offline.save().await {
+        warning!("Failed to save the offline queue: {:#}", e);
+    }
+    let notice = format!("{} will get this when they connect again", held.join(", "));
+    send_server_message(writer, None, key, &notice, color).await
+}
+
+// Send a client what was held for them while they were offline, after any missed messages
+// Messages deleted since are left out, and edited ones are sent as they are now
+async fn send_queued_messages(
+    key: &Key,
+    name: &str,
+    author: &str,
+    writer: &Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    state: &SharedState,
+    history: &History,
+    last_seen: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let queued = state.offline.take(name, author);
+    if let Err(e) = state.offline.save().await {
+        warning!("Failed to save the offline queue: {:#}", e);
+    }
+
+    let mut held = Vec::new();
+    {
+        let history_guard = history.lock().await;
+        for queued in queued {
+            let Ok(mut msg) = decrypt_message(key, &queued.frame) else {
+                continue;
+            };
+            msg.author = queued.author;
+            let stored = history_guard
+                .iter()
+                .find(|stored| stored.id == msg.id && stored.timestamp == msg.timestamp);
+            // Those after the last one seen were just replayed
+            let replayed = msg
+                .id
+                .is_some_and(|id| last_seen.is_some_and(|seen| id > seen));
+            match stored {
+                Some(stored) if stored.is_deleted() || replayed => (),
+                Some(stored) => {
+                    let mut current = stored.clone();
+                    current.queued = Some(true);
+                    held.push(current);
+                }
+                None => held.push(msg),
+            }
+        }
+    }
+    if held.is_empty() {
+        return Ok(());
+    }
+
+    let notice = format!(
+        "You have {} message(s) from while you were away",
+        held.len()
+    );
+    send_server_message(writer, None, key, &notice, SerdeColor::DarkGrey).await?;
+    for msg in &held {
+        let encrypted_msg = encrypt_message(key, msg)?;
+        write_client_frame(&mut *writer.lock().await, &encrypted_msg).await?;
+    }
+
+    // Direct messages reached their recipient at last, the identity that sent them is told
+    for msg in held.iter().filter(|msg| msg.to.is_some()) {
+        if let (Some(sender), Some(id)) = (&msg.author, msg.id) {
+            let receipt = delivery_receipt(name.to_string(), id);
+            send_receipt(key, state, sender, &receipt)?;
+        }
+    }
+    info!("{} got {} message(s) held while offline", name, held.len());
     Ok(())
 }
 
 // Broadcast the message to all clients except the sender
+// It is encrypted once and shared by every queue, the map is read one shard at a time
 async fn broadcast_message(
     key: &Key,
     state: &SharedState,
     sender_id: &usize,
     msg: Message,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let encrypted_message = encrypt_message(&key, &msg)?;
+    let frame: Frame = encrypt_message(key, &msg)?.into();
+    for outbound in outbounds_except(state, sender_id) {
+        outbound.send(frame.clone()).await;
+    }
+
+    Ok(())
+}
+
+// The queues of every client but one, cloned so no shard is locked while a queue waits for room
+fn outbounds_except(state: &SharedState, except_id: &usize) -> Vec<Outbound> {
+    state
+        .clients
+        .iter()
+        .filter(|entry| entry.key() != except_id)
+        .map(|entry| entry.outbound.clone())
+        .collect()
+}
+
+// The queue of whoever is connected with the identity `author`
+fn outbound_of_identity(state: &SharedState, author: &str) -> Option<Outbound> {
+    state
+        .clients
+        .iter()
+        .find(|client| client.author.as_deref() == Some(author))
+        .map(|client| client.outbound.clone())
+}
+
+// Fingerprint of a connected client's identity, what direct messages are shown by
+fn identity_of(state: &SharedState, id: &usize) -> String {
+    state
+        .clients
+        .get(id)
+        .and_then(|client| client.author.clone())
+        .unwrap_or_default()
+}
+
+// Lets everyone else know who just arrived
+async fn announce_join(
+    key: &Key,
+    state: &SharedState,
+    id: &usize,
+    name: &str,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let online = state.clients.len();
+    let joined = format!("{} joined the chat ({} online)", name, online);
+    broadcast_presence(key, state, id, &joined, color).await
+}
+
+// Announce a presence change (join, leave, rename, away...) to everyone except the given client
+async fn broadcast_presence(
+    key: &Key,
+    state: &SharedState,
+    except_id: &usize,
+    event: &str,
+    color: SerdeColor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let presence_msg = Message::new(
+        Some("Server".to_string()),
+        Some(get_timestamp()),
+        Some(event.to_string()),
+        Some(color),
+    );
+
+    broadcast_message(key, state, except_id, presence_msg).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OfflineSection;
 
-    let state = state.lock().await;
-    for (client_id, client) in state.iter() {
-        if client_id != sender_id {
-            let _ = client.tx.send(encrypted_message.clone());
+    // Needs a runtime for the dispatcher
+    fn test_state(key: &str) -> SharedState {
+        let offline = OfflineSection {
+            enabled: false,
+            ..OfflineSection::default()
+        };
+        let (dispatch, dispatch_rx) = mpsc::channel(DISPATCH_QUEUE);
+        task::spawn(dispatch_messages(dispatch_rx));
+        Arc::new(State {
+            clients: DashMap::new(),
+            names: DashMap::new(),
+            mutes: DashMap::new(),
+            invites: DashMap::new(),
+            public_address: ("127.0.0.1".to_string(), 0),
+            offline: OfflineQueue::load(&offline, key),
+            admitted: DashSet::new(),
+            dispatch,
+            joined: AtomicUsize::new(0),
+        })
+    }
+
+    // Registers a client with the identity `<name>'s identity`, returning what is queued for it
+    fn connect(state: &SharedState, id: usize, name: &str) -> broadcast::Receiver<Outgoing> {
+        let (outbound, rx) = Outbound::new(1024, SlowClientPolicy::DropOldest);
+        let mut client = Client::new(name.to_string(), outbound, SerdeColor::Red);
+        client.author = Some(format!("{}'s identity", name));
+        state.clients.insert(id, client);
+        state.names.insert(name.to_string(), id);
+        rx
+    }
+
+    fn received(key: &str, rx: &mut broadcast::Receiver<Outgoing>) -> Vec<Message> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|outgoing| decrypt_message(key, &outgoing.frame).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_claim_name() {
+        let names = DashMap::new();
+        assert_eq!(claim_name(&names, "alice", 1), "alice");
+        assert_eq!(claim_name(&names, "alice", 2), "alice-1");
+        assert_eq!(claim_name(&names, "alice", 3), "alice-2");
+        // The owner keeps its name, and a released name can be claimed again
+        assert_eq!(claim_name(&names, "alice", 1), "alice");
+        names.remove("alice-1");
+        assert_eq!(claim_name(&names, "alice", 4), "alice-1");
+        // Renaming to a suffixed name that is taken suffixes it again
+        assert_eq!(claim_name(&names, "alice-2", 5), "alice-2-1");
+        assert_eq!(names.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_client_slots() {
+        let state = test_state("key");
+        let slots: Vec<_> = (0..3).filter_map(|_| reserve_client(&state, 2)).collect();
+        assert_eq!(slots.len(), 2);
+        assert!(reserve_client(&state, 2).is_none());
+
+        // Leaving frees the place for the next client
+        drop(slots);
+        assert!(reserve_client(&state, 2).is_some());
+        assert_eq!(state.joined.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_flood_mute() {
+        let mut config = ServerConfig::default();
+        config.rate_limits.messages_per_sec = 0.001;
+        config.rate_limits.message_burst = 2;
+        config.rate_limits.mute_after = 3;
+        let config: Config = Arc::new(config);
+        let state = test_state(&generate_key(32));
+        let mut flood = FloodGuard::new("alice's identity".to_string(), &config);
+
+        // The burst goes through, then messages are refused until the user is muted
+        assert!(check_flood(&mut flood, &state, "alice", 10, &config).is_ok());
+        assert!(check_flood(&mut flood, &state, "alice", 10, &config).is_ok());
+        for _ in 0..2 {
+            let refused = check_flood(&mut flood, &state, "alice", 10, &config).unwrap_err();
+            assert!(refused.contains("too fast"));
         }
+        let muted = check_flood(&mut flood, &state, "alice", 10, &config).unwrap_err();
+        assert!(muted.contains("muted for 1m"));
+        assert!(state.mutes.contains_key("alice's identity"));
+
+        // The mute is by identity, so reconnecting with it is still muted
+        let mut reconnected = FloodGuard::new("alice's identity".to_string(), &config);
+        let refused = check_flood(&mut reconnected, &state, "alice", 10, &config).unwrap_err();
+        assert!(refused.contains("try again in"));
+
+        // Someone else from the same address isn't, and text over the byte budget is refused
+        // even with messages left
+        let mut flood = FloodGuard::new("bob's identity".to_string(), &config);
+        assert!(check_flood(&mut flood, &state, "bob", 10, &config).is_ok());
+        let size = config.rate_limits.byte_burst as usize + 1;
+        assert!(check_flood(&mut flood, &state, "bob", size, &config).is_err());
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_bind_policies() {
+        let addresses = vec!["127.0.0.1".to_string()];
+        let taken = bind_all(&addresses, 0).await.unwrap();
+        let port = taken[0].local_addr().unwrap().port();
+        let bind = |bind_policy, port_range_end| BindSection {
+            bind_policy,
+            port_range_end,
+            ..BindSection::default()
+        };
+
+        // A taken port stops the server unless the policy says otherwise
+        let fail = bind(BindPolicy::Fail, None);
+        assert!(setup_tcp_listeners(&addresses, port, &fail).await.is_err());
+
+        // The range moves on to the next free port, or fails once it runs out
+        let end = port.saturating_add(20);
+        let range = bind(BindPolicy::Range, Some(end));
+        let listeners = setup_tcp_listeners(&addresses, port, &range).await.unwrap();
+        let bound = listeners[0].local_addr().unwrap().port();
+        assert!(bound > port && bound <= end);
+        let range = bind(BindPolicy::Range, Some(port));
+        assert!(setup_tcp_listeners(&addresses, port, &range).await.is_err());
+
+        // Any port will do, and scripts can read which one from the port file
+        let path = std::env::temp_dir().join(format!("server-{}.port", generate_key(8)));
+        let any = BindSection {
+            port_file: Some(path.to_string_lossy().into_owned()),
+            ..bind(BindPolicy::Any, None)
+        };
+        let listeners = setup_tcp_listeners(&addresses, port, &any).await.unwrap();
+        let bound = listeners[0].local_addr().unwrap().port();
+        assert_ne!(bound, port);
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.trim(), bound.to_string());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_presence() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        let mut alice = connect(&state, 0, "alice");
+        let mut bob = connect(&state, 1, "bob");
+        let notices = |rx: &mut broadcast::Receiver<Outgoing>| -> Vec<String> {
+            received(&key, rx)
+                .into_iter()
+                .filter_map(|msg| msg.message)
+                .collect()
+        };
+
+        // The others hear about it when someone joins, with how many are online
+        announce_join(&key, &state, &1, "bob", SerdeColor::Blue)
+            .await
+            .unwrap();
+        assert_eq!(notices(&mut alice), ["bob joined the chat (2 online)"]);
+        assert!(notices(&mut bob).is_empty());
+
+        // /who lists everyone by name, marking whoever asked
+        let who = |id| -> Vec<String> {
+            let users = user_list(&id, &state);
+            users.into_iter().map(|(line, _)| line).collect()
+        };
+        assert_eq!(who(0), ["alice (you) - idle 0s", "bob - idle 0s"]);
+
+        // Leaving frees the name and tells the others
+        let assigned_colors: AssignedColors = Arc::default();
+        cleanup_client(&key, state.clone(), &assigned_colors, "bob", &1).await;
+        assert_eq!(notices(&mut alice), ["bob left the chat (1 online)"]);
+        assert!(!state.names.contains_key("bob"));
+        assert_eq!(who(0), ["alice (you) - idle 0s"]);
+    }
+
+    #[tokio::test]
+    async fn test_away_and_typing() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        connect(&state, 0, "alice");
+        let mut bob = connect(&state, 1, "bob");
+        let cooldown = Duration::from_secs(30);
+        let change = |presence| update_presence(presence, "alice", &0, &state, cooldown);
+
+        // Away with a note shows up in /who, changing again right away is refused
+        let event = change(Presence::Away("lunch".into())).unwrap();
+        assert_eq!(event.as_deref(), Some("alice is away: lunch"));
+        let (line, _) = user_list(&1, &state).remove(0);
+        assert_eq!(line, "alice - idle 0s - away (lunch)");
+        let refused = change(Presence::Back).unwrap_err();
+        assert!(refused.contains("too often"));
+
+        // Once the cooldown is over the status and /back apply
+        state.clients.get_mut(&0).unwrap().last_presence_change = None;
+        change(Presence::Status("on call".into())).unwrap();
+        state.clients.get_mut(&0).unwrap().last_presence_change = None;
+        assert_eq!(
+            change(Presence::Back).unwrap().as_deref(),
+            Some("alice is back")
+        );
+        let (line, _) = user_list(&1, &state).remove(0);
+        assert_eq!(line, "alice - idle 0s - \"on call\"");
+
+        // A second typing signal within the cooldown isn't relayed again
+        for _ in 0..2 {
+            relay_typing(&key, &state, "alice", &0, SerdeColor::Red, cooldown)
+                .await
+                .unwrap();
+        }
+        let typing = received(&key, &mut bob);
+        assert_eq!(typing.len(), 1);
+        assert_eq!(typing[0].message.as_deref(), Some(TYPING_SIGNAL));
+    }
+
+    #[tokio::test]
+    async fn test_change_message() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        let history: History = Arc::default();
+        connect(&state, 0, "alice");
+        let msg = Message::new(Some("alice".into()), None, Some("hi".into()), None);
+        store_message_in_history(&key, msg, &0, &state, &history, 10)
+            .await
+            .unwrap();
+
+        // Only the same identity can change it
+        let mallory = Editor::Author("another identity".into());
+        let refused =
+            rewrite_message(&history, &state, 1, None, "another identity", &mallory).await;
+        assert_eq!(
+            refused.unwrap_err(),
+            "You can only change your own messages"
+        );
+        let alice = Editor::Author("alice's identity".into());
+        let text = Some("hello".to_string());
+        let (notice, _) = rewrite_message(&history, &state, 1, text, "alice's identity", &alice)
+            .await
+            .unwrap();
+        assert_eq!(notice.target, Some(1));
+        assert!(notice.edited.is_some());
+        assert_eq!(history.lock().await[0].message.as_deref(), Some("hello"));
+        let own = state.clients.get(&0).unwrap().messages[0].clone();
+        assert_eq!(own.message.as_deref(), Some("hello"));
+
+        // Sudo can delete anyone's, and a deleted message keeps its ID but can't change again
+        let (notice, _) = rewrite_message(&history, &state, 1, None, "", &Editor::Sudo)
+            .await
+            .unwrap();
+        assert_eq!(notice.message.as_deref(), Some(DELETE_SIGNAL));
+        assert!(history.lock().await[0].is_deleted());
+        let again = rewrite_message(&history, &state, 1, None, "alice's identity", &alice).await;
+        assert_eq!(again.unwrap_err(), "This message was deleted");
+        let missing = rewrite_message(&history, &state, 2, None, "alice's identity", &alice).await;
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reactions() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        let history: History = Arc::default();
+        connect(&state, 0, "alice");
+        let msg = Message::new(Some("alice".into()), None, Some("lunch?".into()), None);
+        store_message_in_history(&key, msg, &0, &state, &history, 10)
+            .await
+            .unwrap();
+        let react =
+            |target, emoji, name| toggle_reaction(&history, &state, target, emoji, name, name);
+
+        for (emoji, name) in [("👍", "bob"), ("👍", "carol"), ("🎉", "carol")] {
+            react(1, emoji, name).await.unwrap();
+        }
+        react(1, "🎉", "bob").await.unwrap();
+        let (update, participants) = react(1, "👍", "bob").await.unwrap();
+        assert_eq!(update.target, Some(1));
+        assert_eq!(update.quote.as_deref(), Some("alice: lunch?"));
+        assert_eq!(participants, None);
+        // The same reaction again takes it back, and an emoji nobody uses any more goes away
+        assert_eq!(update.reaction_counts(), " [🎉 2, 👍 1]");
+        let (update, _) = react(1, "👍", "carol").await.unwrap();
+        assert_eq!(update.reaction_counts(), " [🎉 2]");
+
+        // History and the author's messages keep the counts
+        assert_eq!(history.lock().await[0].reaction_counts(), " [🎉 2]");
+        let own = state.clients.get(&0).unwrap().messages[0].clone();
+        assert_eq!(own.reaction_counts(), " [🎉 2]");
+        assert!(react(2, "👍", "bob").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages_follow_identity() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        let history: History = Arc::default();
+        let mut alice = connect(&state, 0, "alice");
+        let mut bob = connect(&state, 1, "bob");
+        let mut msg = Message::new(
+            Some("alice".into()),
+            None,
+            Some("/msg bob psst".into()),
+            None,
+        );
+        msg.author = Some("alice's identity".into());
+        prepare_direct_message(&mut msg, &state).unwrap();
+        assert_eq!(msg.recipient.as_deref(), Some("bob's identity"));
+        store_message_in_history(&key, msg, &0, &state, &history, 10)
+            .await
+            .unwrap();
+        let mut reply = Message::new(Some("bob".into()), None, Some("/reply 1 hi".into()), None);
+        reply.author = Some("bob's identity".into());
+        prepare_reply(&mut reply, &history).await.unwrap();
+        assert_eq!(reply.recipient.as_deref(), Some("alice's identity"));
+        store_message_in_history(&key, reply, &1, &state, &history, 10)
+            .await
+            .unwrap();
+        let delivered = timeout(Duration::from_secs(1), bob.recv()).await.unwrap();
+        let delivered = decrypt_message(&key, &delivered.unwrap().frame).unwrap();
+        assert_eq!(delivered.message.as_deref(), Some("psst"));
+        let replied = timeout(Duration::from_secs(1), alice.recv()).await.unwrap();
+        assert!(replied.is_ok());
+
+        // Bob leaves and someone else takes the name, none of it shows up for them
+        state.clients.remove(&1);
+        state.names.remove("bob");
+        connect(&state, 2, "bob");
+        state.clients.get_mut(&2).unwrap().author = Some("mallory's identity".into());
+        let mallory = "mallory's identity";
+        assert!(!get_global_message_history(history.clone(), mallory)
+            .await
+            .contains("psst"));
+        let query = SearchQuery::parse("from:alice").unwrap();
+        assert_eq!(
+            search_history(&history, mallory, &query).await,
+            "No messages found"
+        );
+        let thread = get_thread(&history, 1, mallory).await;
+        assert_eq!(thread, "There is no such message, it may be too old");
+        let mut reply = Message::new(Some("bob".into()), None, Some("/reply 1 hi".into()), None);
+        reply.author = Some(mallory.into());
+        assert!(prepare_reply(&mut reply, &history).await.is_err());
+        assert!(toggle_reaction(&history, &state, 1, "👀", "bob", mallory)
+            .await
+            .is_err());
+
+        // The identity it was sent to still finds it under any name
+        let thread = get_thread(&history, 1, "bob's identity").await;
+        assert!(thread.contains("alice -> bob: psst") && thread.contains("bob -> alice: hi"));
+    }
+
+    #[tokio::test]
+    async fn test_read_receipts_follow_identity() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        let history: History = Arc::default();
+        let mut alice = connect(&state, 0, "alice");
+        connect(&state, 1, "bob");
+        let msg = Message::new(Some("alice".into()), None, Some("hi".into()), None);
+        store_message_in_history(&key, msg, &0, &state, &history, 10)
+            .await
+            .unwrap();
+        record_read(&key, &state, &history, 1, "bob", "bob's identity")
+            .await
+            .unwrap();
+        let receipts = received(&key, &mut alice);
+        assert_eq!(receipts[0].seen_by, Some(1));
+
+        // Alice leaves and someone else takes the name, the next receipt isn't theirs
+        state.clients.remove(&0);
+        state.names.remove("alice");
+        let mut mallory = connect(&state, 2, "alice");
+        state.clients.get_mut(&2).unwrap().author = Some("mallory's identity".into());
+        connect(&state, 3, "carol");
+        record_read(&key, &state, &history, 1, "carol", "carol's identity")
+            .await
+            .unwrap();
+        assert!(received(&key, &mut mallory).is_empty());
+        assert_eq!(history.lock().await[0].read_by.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_interleaved_senders() {
+        let key: Key = Arc::new(generate_key(32));
+        let state = test_state(&key);
+        let history: History = Arc::default();
+        connect(&state, 0, "alice");
+        connect(&state, 1, "bob");
+        let mut carol = connect(&state, 2, "carol");
+
+        let senders: Vec<_> = [(0, "alice"), (1, "bob")]
+            .into_iter()
+            .map(|(id, name)| {
+                let (key, state, history) = (key.clone(), state.clone(), history.clone());
+                tokio::spawn(async move {
+                    for n in 0..200 {
+                        let msg = Message::new(Some(name.into()), None, Some(n.to_string()), None);
+                        store_message_in_history(&key, msg, &id, &state, &history, 1000)
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+
+        // Whoever stored first, the others get every message in ID order
+        let mut ids = Vec::new();
+        while ids.len() < 400 {
+            let outgoing = timeout(Duration::from_secs(5), carol.recv()).await;
+            let msg = decrypt_message(&key, &outgoing.unwrap().unwrap().frame).unwrap();
+            ids.push(msg.id.unwrap());
+        }
+        assert_eq!(ids, (1..=400).collect::<Vec<u64>>());
+        assert!(received(&key, &mut carol).is_empty());
+
+        // A client that stopped reading under the block policy doesn't hold up storing
+        let (outbound, _stuck) = Outbound::new(1, SlowClientPolicy::Block);
+        let client = Client::new("dave".to_string(), outbound, SerdeColor::Red);
+        state.clients.insert(3, client);
+        for n in 0..5 {
+            let msg = Message::new(Some("alice".into()), None, Some(n.to_string()), None);
+            let stored = store_message_in_history(&key, msg, &0, &state, &history, 1000);
+            assert!(timeout(Duration::from_secs(1), stored).await.is_ok());
+        }
+        assert_eq!(latest_message_id(&*history.lock().await), 405);
+    }
 }
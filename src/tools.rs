@@ -2,15 +2,47 @@ use aes_gcm::aead::{Aead, OsRng};
 use aes_gcm::Key;
 use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
 use anyhow::{anyhow, Context, Ok, Result};
+use blake2::{Blake2s256, Digest};
 use crossterm::style::Color;
 // Import anyhow for error handling
 use chrono::prelude::*;
 use rand::{Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::io::{self, Write};
 use std::net::IpAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant};
+
+use crate::logger::debug;
+
+// Largest frame accepted from the wire (length prefix excluded)
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+// Size of the big-endian u32 length prefix
+pub const FRAME_HEADER_SIZE: usize = 4;
+// How often a blocked broadcast checks whether a slow client caught up
+const BLOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Heartbeat signals, exchanged in both directions and never shown or broadcast
+pub const PING_SIGNAL: &str = "HEARTBEAT_PING";
+pub const PONG_SIGNAL: &str = "HEARTBEAT_PONG";
+// Sent by clients that opted in while their input line is not empty, relayed to the others
+pub const TYPING_SIGNAL: &str = "TYPING";
+// Sent to a client after /nick, the message name carries the new name
+pub const NAME_CHANGE_SIGNAL: &str = "NAME_CHANGE";
+// Sent in place of the text of a deleted message, `target` names the message
+pub const DELETE_SIGNAL: &str = "MESSAGE_DELETED";
+// Sent when the reactions to the `target` message change, `reactions` holds all of them
+pub const REACTION_SIGNAL: &str = "REACTION";
+// Receipts for the `target` message, sent to its author
+// Sent: the server stored it. Delivered: it was written to the DM recipient, named in `name`
+pub const SENT_SIGNAL: &str = "RECEIPT_SENT";
+pub const DELIVERED_SIGNAL: &str = "RECEIPT_DELIVERED";
+// Sent by clients that opted in once they showed the `target` message
+// Relayed to the author with the reader in `name`, and `seen_by` for messages to the room
+pub const READ_SIGNAL: &str = "RECEIPT_READ";
 
 pub enum AdressMode {
     Server,
@@ -23,6 +55,33 @@ pub struct Message {
     pub timestamp: Option<String>,
     pub message: Option<String>,
     pub color: Option<SerdeColor>,
+    pub id: Option<u64>, // Assigned by the server when the message is stored in history
+    // Edits and deletions: ID of the message they change
+    pub target: Option<u64>,
+    // When the text was last changed with /edit, a deleted message keeps its ID but loses its text
+    pub edited: Option<String>,
+    // Replies: ID of the message they answer, and the start of it as the server had it
+    pub reply_to: Option<u64>,
+    pub quote: Option<String>,
+    // Each emoji and who reacted with it, on stored messages and reaction updates
+    pub reactions: Option<BTreeMap<String, Vec<String>>>,
+    // Direct messages: name of the recipient when it was sent, only the two of them see it
+    pub to: Option<String>,
+    // Read receipts for messages to the room: how many have read it
+    pub seen_by: Option<usize>,
+    // Held for the recipient while they were offline, shown even if older than what they last saw
+    pub queued: Option<bool>,
+    // Refusals: the server may let the client in if it tries again later, like when it is full
+    pub retry: Option<bool>,
+    // Kept by the server for read receipts, never sent: identities of who read it
+    #[serde(skip)]
+    pub read_by: Vec<String>,
+    // Kept by the server to check edits and deletions: fingerprint of the author's identity
+    #[serde(skip)]
+    pub author: Option<String>,
+    // Kept by the server for direct messages: fingerprint of the recipient's identity
+    #[serde(skip)]
+    pub recipient: Option<String>,
 }
 
 impl Message {
@@ -37,11 +96,102 @@ impl Message {
             timestamp,
             message,
             color,
+            id: None,
+            target: None,
+            edited: None,
+            reply_to: None,
+            quote: None,
+            reactions: None,
+            to: None,
+            seen_by: None,
+            queued: None,
+            retry: None,
+            read_by: Vec::new(),
+            author: None,
+            recipient: None,
+        }
+    }
+
+    // Messages to the room are visible to everyone, direct messages to the identities on both
+    // sides, so whoever takes one of the names later doesn't see them
+    pub fn visible_to(&self, author: &str) -> bool {
+        match &self.to {
+            Some(_) => [&self.author, &self.recipient]
+                .iter()
+                .any(|side| side.as_deref() == Some(author)),
+            None => true,
+        }
+    }
+
+    // The name and identity of who else sees a direct message, from `author`'s point of view
+    pub fn other_side(&self, author: &str) -> Option<(&str, &str)> {
+        let to = self.to.as_deref()?;
+        if self.author.as_deref() == Some(author) {
+            Some((to, self.recipient.as_deref()?))
+        } else {
+            Some((self.name.as_deref()?, self.author.as_deref()?))
+        }
+    }
+
+    // Identities on both sides of a direct message, None for messages to the room
+    pub fn participants(&self) -> Option<Vec<String>> {
+        self.to.as_ref()?;
+        Some(self.author.iter().chain(&self.recipient).cloned().collect())
+    }
+
+    // The author as listed in history, like `alice` or `alice -> bob` for a direct message
+    pub fn sender_label(&self) -> String {
+        let name = self.name.as_deref().unwrap_or("Unknown");
+        match &self.to {
+            Some(to) => format!("{} -> {}", name, to),
+            None => name.to_string(),
+        }
+    }
+
+    // Clears what only the server sets on messages from clients, so it can't be forged
+    pub fn clear_server_fields(&mut self) {
+        self.id = None;
+        self.target = None;
+        self.edited = None;
+        self.reply_to = None;
+        self.quote = None;
+        self.reactions = None;
+        self.to = None;
+        self.seen_by = None;
+        self.queued = None;
+        self.retry = None;
+    }
+
+    // Only stored messages can be deleted, they stay in history without their text
+    pub fn is_deleted(&self) -> bool {
+        self.id.is_some() && self.message.is_none()
+    }
+
+    // Shown after the text of a message changed with /edit
+    pub fn edited_mark(&self) -> &'static str {
+        match self.edited {
+            Some(_) => " (edited)",
+            None => "",
+        }
+    }
+
+    // Shown after the text of a message people reacted to, like ` [👍 2, 🎉 1]`
+    pub fn reaction_counts(&self) -> String {
+        match &self.reactions {
+            Some(reactions) if !reactions.is_empty() => {
+                let counts: Vec<String> = reactions
+                    .iter()
+                    .map(|(emoji, names)| format!("{} {}", emoji, names.len()))
+                    .collect();
+                format!(" [{}]", counts.join(", "))
+            }
+            _ => String::new(),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[allow(dead_code)]
 pub struct MessageData {
     pub message: Message,
     pub sender_id: usize,
@@ -52,6 +202,13 @@ pub struct Handshake {
     pub name: String,
     pub buffer_size: usize,
     pub color: Option<SerdeColor>,
+    // Client: last message ID seen when resuming a session
+    // Server: latest message ID in history at the time of the handshake
+    pub last_seen: Option<u64>,
+    // Client: ID of the invite it joined with, only sent on the first connection
+    pub invite: Option<String>,
+    // Client: random secret kept across reconnects, its messages stay its own after a /nick too
+    pub identity: Option<String>,
 }
 
 impl Handshake {
@@ -60,32 +217,157 @@ impl Handshake {
             name,
             buffer_size,
             color,
+            last_seen: None,
+            invite: None,
+            identity: None,
+        }
+    }
+}
+
+// What happens once a client's outbound queue is full
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClientPolicy {
+    #[default]
+    DropOldest, // The oldest queued messages are dropped and the client is told how many
+    Disconnect, // The client is told it fell behind and disconnected, it resumes on reconnect
+    Block,      // Whoever broadcasts waits until the client catches up
+}
+
+// An encrypted frame, shared by every queue it is broadcast to instead of copied
+pub type Frame = Arc<[u8]>;
+
+// A queued frame, with the direct message it carries when its author wants a delivery receipt
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    pub frame: Frame,
+    pub delivery: Option<Delivery>,
+}
+
+// The author to tell once the recipient's sender task wrote the message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub author: usize, // Client ID
+    pub message: u64,
+    pub recipient: String,
+}
+
+// Bounded queue of encrypted frames waiting to be written to one client
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    pub tx: broadcast::Sender<Outgoing>,
+    pub capacity: usize,
+    pub policy: SlowClientPolicy,
+}
+
+impl Outbound {
+    pub fn new(capacity: usize, policy: SlowClientPolicy) -> (Self, broadcast::Receiver<Outgoing>) {
+        let (tx, rx) = broadcast::channel(capacity);
+        let outbound = Outbound {
+            tx,
+            capacity,
+            policy,
+        };
+        (outbound, rx)
+    }
+
+    // Queues a frame, waiting for room first when the policy is to block
+    pub async fn send(&self, frame: Frame) {
+        self.send_tracked(frame, None).await;
+    }
+
+    // Queues a frame only when there is room, whatever the policy, for what can be lost
+    pub fn try_send(&self, frame: Frame) -> bool {
+        if self.tx.len() >= self.capacity {
+            return false;
+        }
+        let outgoing = Outgoing {
+            frame,
+            delivery: None,
+        };
+        self.tx.send(outgoing).is_ok()
+    }
+
+    pub async fn send_tracked(&self, frame: Frame, delivery: Option<Delivery>) {
+        if self.policy == SlowClientPolicy::Block {
+            while self.tx.receiver_count() > 0 && self.tx.len() >= self.capacity {
+                tokio::time::sleep(BLOCK_POLL_INTERVAL).await;
+            }
+        }
+        let _ = self.tx.send(Outgoing { frame, delivery });
+    }
+}
+
+// Longest time between pings and most pings missed in a row, so the timeout can't overflow
+pub const MAX_HEARTBEAT_INTERVAL_SECS: u64 = 3600;
+pub const MAX_HEARTBEAT_MISSED: u32 = 100;
+
+// Heartbeat settings, used by both the server and the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration, // Time between pings
+    pub max_missed: u32,    // Unanswered intervals before the peer is considered dead
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(15),
+            max_missed: 3,
         }
     }
 }
 
+impl HeartbeatConfig {
+    // How long the peer may stay silent before the connection is dropped
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.max_missed
+    }
+}
+
 // Estructura del cliente
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct Client {
     pub name: String,
-    pub tx: broadcast::Sender<Vec<u8>>,
+    pub outbound: Outbound,
     pub color: SerdeColor,
     pub messages: VecDeque<Message>, // Efficient data structure for storing messages
     pub sudo: bool,
+    pub last_active: Instant, // Last time the user sent a message or command
+    pub away: Option<String>, // Away reason, empty when none was given
+    pub status: Option<String>, // Custom status text
+    pub last_presence_change: Option<Instant>, // Used to rate-limit /away, /back and /status
+    pub last_typing: Option<Instant>, // Used to rate-limit typing notifications
+    pub author: Option<String>, // Fingerprint of the identity given in the handshake
 }
 
 impl Client {
-    pub fn new(name: String, tx: broadcast::Sender<Vec<u8>>, color: SerdeColor) -> Self {
+    pub fn new(name: String, outbound: Outbound, color: SerdeColor) -> Self {
         Client {
             name,
-            tx,
+            outbound,
             color,
             messages: VecDeque::new(),
             sudo: false,
+            last_active: Instant::now(),
+            away: None,
+            status: None,
+            last_presence_change: None,
+            last_typing: None,
+            author: None,
         }
     }
 
+    // Mark the user as active now
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
     pub fn add_message(&mut self, message: Message) {
         self.messages.push_back(message);
     }
@@ -98,11 +380,15 @@ impl Client {
         let messages = self
             .messages
             .iter()
+            .filter(|msg| !msg.is_deleted())
             .map(|msg| {
                 format!(
-                    "{}: {}",
+                    "#{} {}: {}{}{}",
+                    msg.id.unwrap_or_default(),
                     msg.timestamp.clone().unwrap_or("Unknown".to_string()),
-                    msg.message.as_deref().unwrap_or("")
+                    msg.message.as_deref().unwrap_or(""),
+                    msg.edited_mark(),
+                    msg.reaction_counts()
                 )
             })
             .collect::<Vec<String>>()
@@ -123,25 +409,211 @@ pub enum ServerCommand {
     ViewHistory,
     ViewKey,
     ChangeColor,
+    Who,
+    Away,
+    Back,
+    Status,
+    Nick,
+    Invite,
+    Edit,
+    Delete,
+    Reply,
+    Thread,
+    React,
+    Msg,
+    Mentions,
+    Search,
     Invalid,
 }
 
 impl ServerCommand {
+    // Only the first word selects the command, see command_argument for the rest
     pub fn from_str(command: &str) -> Self {
-        match command {
+        match command.split_whitespace().next().unwrap_or_default() {
             "/close" => ServerCommand::Close,
             "/view-messages" => ServerCommand::ViewMessages,
             "/view-key" => ServerCommand::ViewKey,
             "/view-history" => ServerCommand::ViewHistory,
             "/change-color" => ServerCommand::ChangeColor,
+            "/who" => ServerCommand::Who,
+            "/away" => ServerCommand::Away,
+            "/back" => ServerCommand::Back,
+            "/status" => ServerCommand::Status,
+            "/nick" => ServerCommand::Nick,
+            "/invite" => ServerCommand::Invite,
+            "/edit" => ServerCommand::Edit,
+            "/delete" => ServerCommand::Delete,
+            "/reply" => ServerCommand::Reply,
+            "/thread" => ServerCommand::Thread,
+            "/react" => ServerCommand::React,
+            "/msg" => ServerCommand::Msg,
+            "/mentions" => ServerCommand::Mentions,
+            "/search" => ServerCommand::Search,
             _ => ServerCommand::Invalid,
         }
     }
 }
 
+// The conversation a message belongs to: its root and every reply below it, in order
+// Each message comes with its depth, replies to messages no longer in history are left out
+// Every message is visited once, so replies going round in circles can't loop forever
+pub fn collect_thread(history: &VecDeque<Message>, id: u64) -> Vec<(usize, &Message)> {
+    let find = |id: u64| history.iter().find(|msg| msg.id == Some(id));
+    let Some(mut root) = find(id) else {
+        return Vec::new();
+    };
+    let mut visited = HashSet::from([root.id]);
+    while let Some(parent) = root.reply_to.and_then(find) {
+        if !visited.insert(parent.id) {
+            break;
+        }
+        root = parent;
+    }
+
+    let mut visited = HashSet::from([root.id]);
+    let mut thread = Vec::new();
+    let mut pending = vec![(0, root)];
+    while let Some((depth, msg)) = pending.pop() {
+        thread.push((depth, msg));
+        // Pushed newest first so the oldest reply comes out next
+        let replies: Vec<&Message> = history
+            .iter()
+            .rev()
+            .filter(|reply| reply.reply_to.is_some() && reply.reply_to == msg.id)
+            .filter(|reply| visited.insert(reply.id))
+            .collect();
+        pending.extend(replies.into_iter().map(|reply| (depth + 1, reply)));
+    }
+    thread
+}
+
+// Start of a message for quotes and previews, cut on a character boundary
+pub fn snippet(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+// Names mentioned in a message as `@name`, once each
+// Punctuation right after the name isn't part of it, so `@bob,` mentions bob
+pub fn mentions(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for (_, name) in mention_words(text) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+// Where `name` is mentioned in a message, as byte ranges covering `@name`
+// Only whole mentions count, `@bobby` or `me@bob` don't mention bob
+pub fn mention_spans(text: &str, name: &str) -> Vec<Range<usize>> {
+    mention_words(text)
+        .filter(|(_, mentioned)| *mentioned == name)
+        .map(|(start, _)| start..start + 1 + name.len())
+        .collect()
+}
+
+// Every `@name` in a message with the byte offset it starts at
+fn mention_words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut offset = 0;
+    text.split_inclusive(char::is_whitespace)
+        .filter_map(move |word| {
+            let start = offset;
+            offset += word.len();
+            let name = word.trim_end().strip_prefix('@')?;
+            let name = name.trim_end_matches(|c: char| c.is_ascii_punctuation() && c != '_');
+            (!name.is_empty()).then_some((start, name))
+        })
+}
+
+const SEARCH_USAGE: &str = "Usage: /search <words> [from:<name>] [since:<YYYY-MM-DD>] [page:<n>]";
+
+// What `/search` looks for, each part given has to match
+#[derive(Debug, PartialEq)]
+pub struct SearchQuery {
+    pub words: Vec<String>, // Lowercased, all of them have to be in the text
+    pub from: Option<String>,
+    pub since: Option<NaiveDate>, // Compared with the date the message was sent
+    pub page: usize,              // Starting at 1
+}
+
+impl SearchQuery {
+    pub fn parse(argument: &str) -> Result<Self, String> {
+        let mut query = SearchQuery {
+            words: Vec::new(),
+            from: None,
+            since: None,
+            page: 1,
+        };
+        for word in argument.split_whitespace() {
+            if let Some(name) = word.strip_prefix("from:") {
+                query.from = Some(name.to_string()).filter(|name| !name.is_empty());
+            } else if let Some(date) = word.strip_prefix("since:") {
+                let since = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|_| format!("`{}` is not a date like 2024-09-12", date))?;
+                query.since = Some(since);
+            } else if let Some(page) = word.strip_prefix("page:") {
+                query.page = page
+                    .parse()
+                    .ok()
+                    .filter(|page| *page > 0)
+                    .ok_or_else(|| format!("`{}` is not a page number", page))?;
+            } else {
+                query.words.push(word.to_lowercase());
+            }
+        }
+        if query.words.is_empty() && query.from.is_none() && query.since.is_none() {
+            return Err(SEARCH_USAGE.to_string());
+        }
+        std::result::Result::Ok(query)
+    }
+
+    // Deleted messages never match
+    pub fn matches(&self, msg: &Message) -> bool {
+        let Some(text) = msg.message.as_deref() else {
+            return false;
+        };
+        if let Some(from) = &self.from {
+            if !msg
+                .name
+                .as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(from))
+            {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            // Timestamps start with the date, see get_timestamp
+            let sent = msg
+                .timestamp
+                .as_deref()
+                .and_then(|timestamp| timestamp.get(..10))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            if sent.is_none_or(|sent| sent < since) {
+                return false;
+            }
+        }
+        let text = text.to_lowercase();
+        self.words.iter().all(|word| text.contains(word.as_str()))
+    }
+}
+
+// Everything after the command word, trimmed
+pub fn command_argument(command: &str) -> &str {
+    let command = command.trim_start();
+    command
+        .find(char::is_whitespace)
+        .map_or("", |index| command[index..].trim())
+}
+
 pub enum ClientCommand {
     Quit,
     ToogleColor,
+    ToggleTyping,
+    ToggleReceipts,
     Help,
     Invalid,
 }
@@ -151,6 +623,8 @@ impl ClientCommand {
         match command {
             "/quit" => ClientCommand::Quit,
             "/toggle-color" => ClientCommand::ToogleColor,
+            "/typing" => ClientCommand::ToggleTyping,
+            "/receipts" => ClientCommand::ToggleReceipts,
             "/help" => ClientCommand::Help,
             _ => ClientCommand::Invalid,
         }
@@ -234,71 +708,6 @@ impl From<SerdeColor> for crossterm::style::Color {
     }
 }
 
-// loogger
-// #[derive(Clone)]
-// pub enum Mode {
-//     Foreground,
-//     Background,
-// }
-
-// #[derive(Clone)]
-// pub struct Logger {
-//     pub mode: Mode,
-//     pub log_buffer: Arc<Mutex<Vec<String>>>,
-// }
-
-// impl Logger {
-//     pub fn new(mode: Mode) -> Logger {
-//         Logger {
-//             mode,
-//             log_buffer: Arc::new(Mutex::new(Vec::new())),
-//         }
-//     }
-
-//     // Logs a message based on the mode
-//     pub fn log(&self, message: &str) {
-//         match self.mode {
-//             Mode::Foreground => {
-//                 println!("{}", message); // Display the message immediately
-//             }
-//             Mode::Background => {
-//                 let mut log_buffer = self.log_buffer.lock().unwrap();
-//                 log_buffer.push(message.to_string()); // Save the message in buffer
-//             }
-//         }
-//     }
-
-//     // Switch logger mode
-//     #[allow(dead_code)]
-//     pub fn set_mode(&mut self, mode: Mode) {
-//         self.mode = mode;
-//     }
-
-//     // Display saved logs
-//     #[allow(dead_code)]
-//     pub fn show_saved_logs(&self) {
-//         let log_buffer = self.log_buffer.lock().unwrap();
-//         for log in log_buffer.iter() {
-//             println!("{}", log);
-//         }
-//     }
-
-//     // Save logs to a file
-//     #[allow(dead_code)]
-//     pub fn save_to_file(&self, file_path: &str) {
-//         let log_buffer = self.log_buffer.lock().unwrap();
-//         let mut file = OpenOptions::new()
-//             .create(true)
-//             .append(true)
-//             .open(file_path)
-//             .unwrap();
-
-//         for log in log_buffer.iter() {
-//             writeln!(file, "{}", log).unwrap();
-//         }
-//     }
-// }
-
 // Conversión de HSL a RGB usando crossterm::style::Color
 fn hsl_to_termcolor(h: f64, s: f64, l: f64) -> Color {
     let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
@@ -331,10 +740,39 @@ pub fn random_color() -> SerdeColor {
     ))
 }
 
+// Accepts IP addresses, IPv6 with or without brackets, and host names
+// Returns the host without brackets, ready to be resolved or bound
+pub fn parse_host(input: &str) -> Option<String> {
+    let host = input
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(input);
+    if host.parse::<IpAddr>().is_ok() {
+        return Some(host.to_string());
+    }
+
+    // Labels of letters, digits and hyphens, not starting or ending with a hyphen
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    (host.len() <= 253 && host.split('.').all(valid_label)).then(|| host.to_string())
+}
+
+// Host and port as shown to users, IPv6 addresses go between brackets
+pub fn format_address(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        std::result::Result::Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
 pub fn get_ip(ip: Option<&str>, message: Option<&str>, mode: AdressMode) -> Result<String> {
     if let Some(ip) = ip {
-        println!("[Tools-Debug] Destination IP: {}", ip);
-        Ok(ip.to_string())
+        debug!("Destination IP: {}", ip);
+        parse_host(ip).ok_or_else(|| anyhow!("Invalid IP address or host name `{}`", ip))
     } else {
         loop {
             if let Some(ref message) = message {
@@ -361,10 +799,9 @@ pub fn get_ip(ip: Option<&str>, message: Option<&str>, mode: AdressMode) -> Resu
             };
             let host = if host.is_empty() { default_ip } else { host };
 
-            if host.parse::<IpAddr>().is_ok() {
-                return Ok(host.to_string());
-            } else {
-                println!("Invalid IP address.");
+            match parse_host(host) {
+                Some(host) => return Ok(host),
+                None => println!("Invalid IP address or host name."),
             }
         }
     }
@@ -424,6 +861,71 @@ pub fn get_timestamp() -> String {
     )
 }
 
+// Format a duration in a compact human readable way (e.g. "1h 05m", "3m 12s", "40s")
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+// Records now if at least `cooldown` passed since `last`, otherwise returns the time left to wait
+pub fn check_cooldown(
+    last: &mut Option<Instant>,
+    cooldown: Duration,
+) -> std::result::Result<(), Duration> {
+    if let Some(last) = last {
+        let elapsed = last.elapsed();
+        if elapsed < cooldown {
+            return Err(cooldown - elapsed);
+        }
+    }
+    *last = Some(Instant::now());
+    std::result::Result::Ok(())
+}
+
+// Rate limiter holding up to `burst` tokens, refilled at `rate` tokens per second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Starts full, so a burst is allowed right away
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Takes a token if one is available
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_n(1.0)
+    }
+
+    // Takes `amount` tokens at once, or none if there aren't enough
+    pub fn try_take_n(&mut self, amount: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
 pub fn get_user_input(prompt: Option<&str>) -> String {
     if let Some(prompt) = prompt {
         print!("> {}", prompt);
@@ -441,6 +943,24 @@ pub fn generate_key(length: usize) -> String {
     hex::encode(key)
 }
 
+// Short digest of a key to tell servers apart and check a key before using it
+// The key can't be recovered from it, so it is safe to show and announce
+pub fn key_fingerprint(key: &str) -> String {
+    key_digest(key)
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// The bytes behind the fingerprint, for formats that carry it in binary
+pub fn key_digest(key: &str) -> [u8; 8] {
+    let digest = Blake2s256::digest(key.trim().to_lowercase().as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    bytes
+}
+
 // Convert a hexadecimal string to a byte array
 fn hex_to_bytes(hex_str: &str) -> Result<Vec<u8>> {
     hex::decode(hex_str).map_err(|e| anyhow!("Hex decode error: {:?}", e))
@@ -457,12 +977,8 @@ pub fn encrypt<T: Serialize>(key_str: &str, data: &T) -> Result<Vec<u8>> {
     let key = hex_to_bytes(key_str)?;
 
     // Serialize the struct to a JSON string
-    let mut serialized_data = serde_json::to_vec(data)?;
-
-    // Trim the data if it exceeds 1024 bytes
-    if serialized_data.len() > 1024 {
-        serialized_data.truncate(1024);
-    }
+    // Size is bounded by the frame limit and the server's message size cap
+    let serialized_data = serde_json::to_vec(data)?;
 
     // Generate a random 12-byte nonce
     let nonce = {
@@ -491,8 +1007,10 @@ pub fn encrypt<T: Serialize>(key_str: &str, data: &T) -> Result<Vec<u8>> {
 pub fn decrypt<T: for<'de> Deserialize<'de>>(key_str: &str, ciphertext: &[u8]) -> Result<T> {
     let key = hex_to_bytes(key_str)?;
 
-    // Split the nonce and the ciphertext
-    let (nonce_bytes, ciphertext) = ciphertext.split_at(12);
+    // Split the nonce and the ciphertext, anyone can send a frame too short to hold a nonce
+    let (nonce_bytes, ciphertext) = ciphertext
+        .split_at_checked(12)
+        .ok_or_else(|| anyhow!("Encryption error: the data is too short"))?;
 
     // Initialize the cipher
     let cipher = Aes256Gcm::new(Key::<aes_gcm::aes::Aes256>::from_slice(&key));
@@ -509,6 +1027,46 @@ pub fn decrypt<T: for<'de> Deserialize<'de>>(key_str: &str, ciphertext: &[u8]) -
     Ok(data)
 }
 
+// Write a frame to the stream, prefixed with its length as a big-endian u32
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Frame of {} bytes exceeds the maximum size", payload.len()),
+        ));
+    }
+
+    // A single write, a separate header would wait on the peer's delayed ACK (Nagle)
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+// Read a length-prefixed frame from the stream, None means the peer closed the connection
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; FRAME_HEADER_SIZE];
+    if let Err(e) = reader.read_exact(&mut length).await {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Result::Ok(None),
+            _ => Err(e),
+        };
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the maximum size", length),
+        ));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    io::Result::Ok(Some(payload))
+}
+
 // Encrypt a Message
 pub fn encrypt_message(key_str: &str, message: &Message) -> Result<Vec<u8>> {
     encrypt(key_str, message)
@@ -530,7 +1088,6 @@ pub fn decrypt_handshake(key_str: &str, ciphertext: &[u8]) -> Result<Handshake>
 }
 
 #[cfg(test)]
-
 mod tests {
     use super::*;
     use anyhow::Result;
@@ -538,11 +1095,148 @@ mod tests {
     use serde::{Deserialize, Serialize};
     // Define a struct for testing purposes
     #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[allow(dead_code)]
     struct TestData {
         message: String,
         number: u32,
     }
 
+    #[test]
+    fn test_threads() {
+        let message = |id: u64, reply_to: Option<u64>| {
+            let mut msg = Message::new(None, None, Some(format!("m{}", id)), None);
+            msg.id = Some(id);
+            msg.reply_to = reply_to;
+            msg
+        };
+        let history: VecDeque<Message> = VecDeque::from(vec![
+            message(1, None),
+            message(2, Some(1)),
+            message(3, None),
+            message(4, Some(2)),
+            message(5, Some(1)),
+            message(6, Some(99)), // Answers a message that left history
+        ]);
+        let ids = |id| -> Vec<(usize, u64)> {
+            collect_thread(&history, id)
+                .into_iter()
+                .map(|(depth, msg)| (depth, msg.id.unwrap()))
+                .collect()
+        };
+        assert_eq!(ids(4), [(0, 1), (1, 2), (2, 4), (1, 5)]);
+        assert_eq!(ids(3), [(0, 3)]);
+        assert_eq!(ids(6), [(0, 6)]);
+        assert!(ids(7).is_empty());
+
+        assert_eq!(snippet("héllo world", 5), "héllo...");
+        assert_eq!(snippet("hi", 5), "hi");
+    }
+
+    #[test]
+    fn test_thread_cycles() {
+        let message = |id: u64, reply_to: u64| {
+            let mut msg = Message::new(None, None, Some(format!("m{}", id)), None);
+            msg.id = Some(id);
+            msg.reply_to = Some(reply_to);
+            msg
+        };
+        // Forged replies: one to itself, and two answering each other
+        let history = VecDeque::from(vec![message(1, 1), message(2, 3), message(3, 2)]);
+        let ids = |id| -> Vec<(usize, u64)> {
+            collect_thread(&history, id)
+                .into_iter()
+                .map(|(depth, msg)| (depth, msg.id.unwrap()))
+                .collect()
+        };
+        assert_eq!(ids(1), [(0, 1)]);
+        assert_eq!(ids(2), [(0, 3), (1, 2)]);
+        assert_eq!(ids(3), [(0, 2), (1, 3)]);
+
+        // Clients can't set what the server owns
+        let mut msg = message(4, 1);
+        msg.quote = Some("alice: hi".into());
+        msg.clear_server_fields();
+        assert_eq!(msg, Message::new(None, None, Some("m4".into()), None));
+    }
+
+    #[test]
+    fn test_mentions() {
+        assert_eq!(
+            mentions("@bob, ask @carol-2 and @bob! not me@x or @"),
+            ["bob", "carol-2"]
+        );
+    }
+
+    #[test]
+    fn test_mention_spans() {
+        let text = "@bob, not @bobby or me@bob\tbut @bob!";
+        let spans = mention_spans(text, "bob");
+        assert_eq!(spans, [0..4, 31..35]);
+        assert!(spans.iter().all(|span| &text[span.clone()] == "@bob"));
+        assert_eq!(mention_spans("héllo @bob", "bob")[0], 7..11);
+        assert!(mention_spans("@bobby", "bob").is_empty());
+    }
+
+    #[test]
+    fn test_search_queries() {
+        let query = SearchQuery::parse("Lunch from:alice since:2024-09-12 page:2").unwrap();
+        assert_eq!(query.words, ["lunch"]);
+        assert_eq!(query.from.as_deref(), Some("alice"));
+        assert_eq!(query.page, 2);
+        assert!(SearchQuery::parse("").is_err());
+        assert!(SearchQuery::parse("lunch since:yesterday").is_err());
+        assert!(SearchQuery::parse("lunch page:0").is_err());
+
+        let mut msg = Message::new(
+            Some("Alice".into()),
+            Some("2024-09-12 12:00:00".into()),
+            Some("Lunch at noon?".into()),
+            None,
+        );
+        assert!(query.matches(&msg));
+        msg.timestamp = Some("2024-09-11 23:59:59".into());
+        assert!(!query.matches(&msg));
+        assert!(SearchQuery::parse("noon lunch").unwrap().matches(&msg));
+        assert!(!SearchQuery::parse("lunch dinner").unwrap().matches(&msg));
+        assert!(!SearchQuery::parse("from:bob").unwrap().matches(&msg));
+    }
+
+    #[test]
+    fn test_direct_messages() {
+        let mut msg = Message::new(Some("alice".into()), None, Some("hi".into()), None);
+        msg.author = Some("alice's identity".into());
+        assert!(msg.visible_to("carol's identity"));
+        assert_eq!(msg.participants(), None);
+
+        // Both sides are known by identity, the names are only shown
+        msg.to = Some("bob".into());
+        msg.recipient = Some("bob's identity".into());
+        assert!(msg.visible_to("alice's identity") && msg.visible_to("bob's identity"));
+        assert!(!msg.visible_to("carol's identity") && !msg.visible_to("bob"));
+        assert_eq!(
+            msg.other_side("bob's identity"),
+            Some(("alice", "alice's identity"))
+        );
+        assert_eq!(
+            msg.other_side("alice's identity"),
+            Some(("bob", "bob's identity"))
+        );
+        let participants = vec!["alice's identity".to_string(), "bob's identity".to_string()];
+        assert_eq!(msg.participants(), Some(participants));
+        assert_eq!(msg.sender_label(), "alice -> bob");
+
+        // Who read it stays on the server
+        msg.read_by.push("bob".into());
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(!json.contains("read_by"));
+
+        // Only the server makes a message direct, or marks it as held while offline
+        msg.queued = Some(true);
+        msg.clear_server_fields();
+        assert!(msg.visible_to("carol's identity"));
+        assert_eq!(msg.queued, None);
+    }
+
     #[test]
     fn test_aes_encryption_decryption() -> Result<()> {
         // Define a shared key (32 bytes for AES-256)
@@ -554,6 +1248,19 @@ mod tests {
             timestamp: Some("2024-09-12T12:34:56Z".to_string()),
             message: Some("Hello, Bob!".to_string()),
             color: Some(SerdeColor::Red),
+            id: Some(1),
+            target: None,
+            edited: None,
+            reply_to: None,
+            quote: None,
+            reactions: None,
+            to: None,
+            seen_by: None,
+            queued: None,
+            retry: None,
+            read_by: Vec::new(),
+            author: None,
+            recipient: None,
         };
 
         // Encrypt and Decrypt a Message
@@ -568,6 +1275,9 @@ mod tests {
             name: "Alice".to_string(),
             buffer_size: 1024,
             color: Some(SerdeColor::Blue),
+            last_seen: Some(1),
+            invite: None,
+            identity: None,
         };
 
         // Encrypt and Decrypt a Handshake
@@ -581,6 +1291,11 @@ mod tests {
         assert_eq!(message, decrypted_message);
         assert_eq!(handshake, decrypted_handshake);
 
+        // Frames from peers without the key are refused, whatever their size
+        for size in [0, 11, 12, 40] {
+            assert!(decrypt_message(&key, &vec![7u8; size]).is_err());
+        }
+
         Ok(())
     }
 
@@ -607,10 +1322,121 @@ mod tests {
 
         // Check if each component of the timestamp is within a valid range
         assert!(year > 1970, "Year is out of range");
-        assert!(month >= 1 && month <= 12, "Month is out of range");
-        assert!(day >= 1 && day <= 31, "Day is out of range");
+        assert!((1..=12).contains(&month), "Month is out of range");
+        assert!((1..=31).contains(&day), "Day is out of range");
         assert!(hour < 24, "Hour is out of range");
         assert!(minute < 60, "Minute is out of range");
         assert!(second < 60, "Second is out of range");
     }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(40)), "40s");
+        assert_eq!(format_duration(Duration::from_secs(192)), "3m 12s");
+        assert_eq!(format_duration(Duration::from_secs(3900)), "1h 05m");
+    }
+
+    #[test]
+    fn test_command_parsing() {
+        assert!(matches!(
+            ServerCommand::from_str("/away  out for lunch"),
+            ServerCommand::Away
+        ));
+        assert_eq!(command_argument("/away  out for lunch "), "out for lunch");
+        assert_eq!(command_argument("/back"), "");
+    }
+
+    #[test]
+    fn test_check_cooldown() {
+        let mut last = None;
+        assert!(check_cooldown(&mut last, Duration::from_secs(5)).is_ok());
+        assert!(check_cooldown(&mut last, Duration::from_secs(5)).is_err());
+        assert!(check_cooldown(&mut last, Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn test_hosts() {
+        assert_eq!(parse_host("[::1]").as_deref(), Some("::1"));
+        assert_eq!(parse_host("fe80::1").as_deref(), Some("fe80::1"));
+        assert_eq!(
+            parse_host("chat.example.com").as_deref(),
+            Some("chat.example.com")
+        );
+        assert_eq!(parse_host("bad host"), None);
+        assert_eq!(parse_host("-bad.example"), None);
+        assert_eq!(format_address("::1", 5555), "[::1]:5555");
+        assert_eq!(format_address("localhost", 5555), "localhost:5555");
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(100.0, 2.0);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take_n(3.0));
+    }
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() -> Result<()> {
+        let (mut client, mut server) = tokio::io::duplex(MAX_FRAME_SIZE * 2);
+
+        // Two frames written back to back must be read back separately
+        write_frame(&mut client, b"first").await?;
+        write_frame(&mut client, b"second").await?;
+        drop(client);
+
+        assert_eq!(read_frame(&mut server).await?, Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut server).await?, Some(b"second".to_vec()));
+        assert_eq!(read_frame(&mut server).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_size_limit() {
+        let (mut client, _server) = tokio::io::duplex(16);
+        let payload = vec![0u8; MAX_FRAME_SIZE + 1];
+
+        assert!(write_frame(&mut client, &payload).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_outbound_policies() {
+        // Dropping the oldest reports how many were missed
+        let (outbound, mut rx) = Outbound::new(2, SlowClientPolicy::DropOldest);
+        for frame in 1..=3u8 {
+            outbound.send(Frame::from([frame])).await;
+        }
+        assert!(matches!(
+            rx.recv().await,
+            Err(broadcast::error::RecvError::Lagged(1))
+        ));
+        assert_eq!(*rx.recv().await.unwrap().frame, [2]);
+
+        // Blocking waits until the client reads
+        let (outbound, mut rx) = Outbound::new(1, SlowClientPolicy::Block);
+        outbound.send(Frame::from([1])).await;
+        let blocked = tokio::spawn(async move { outbound.send(Frame::from([2])).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        assert_eq!(*rx.recv().await.unwrap().frame, [1]);
+        blocked.await.unwrap();
+        assert_eq!(*rx.recv().await.unwrap().frame, [2]);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_try_send() {
+        // Never waits for a blocked client, nor pushes out what is queued
+        for policy in [SlowClientPolicy::Block, SlowClientPolicy::DropOldest] {
+            let (outbound, mut rx) = Outbound::new(1, policy);
+            assert!(outbound.try_send(Frame::from([1])));
+            assert!(!outbound.try_send(Frame::from([2])));
+            assert_eq!(*rx.recv().await.unwrap().frame, [1]);
+            assert!(rx.try_recv().is_err());
+        }
+    }
 }